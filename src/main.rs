@@ -1,22 +1,33 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(feature = "usbip"), no_std)]
+#![cfg_attr(not(feature = "usbip"), no_main)]
 #![feature(asm)]
 #![feature(collections)]
 #![feature(alloc)]
 #![feature(drop_types_in_const)]
+#![feature(const_fn)]
 
+#[cfg(not(feature = "usbip"))]
 mod render;
 mod usb;
+#[cfg(feature = "usbip")]
+mod usbip;
 extern crate stm32f7_discovery as stm32f7;
 
 // initialization routines for .data and .bss
+#[cfg(not(feature = "usbip"))]
 extern crate r0;
+#[cfg(not(feature = "usbip"))]
 extern crate cortex_m;
+#[cfg(feature = "usbip")]
+extern crate core;
 extern crate collections;
 extern crate alloc;
 
-use stm32f7::{system_clock, board, embedded, lcd, sdram};
+#[cfg(not(feature = "usbip"))]
+use stm32f7::{system_clock, embedded, lcd, sdram, i2c};
+use stm32f7::board;
 
+#[cfg(not(feature = "usbip"))]
 #[no_mangle]
 pub unsafe extern "C" fn reset() -> ! {
 	extern "C" {
@@ -45,6 +56,16 @@ pub unsafe extern "C" fn reset() -> ! {
 	main(board::hw());
 }
 
+#[cfg(feature = "usbip")]
+fn main() {
+	let addr = ::std::env::args().nth(1).unwrap_or("127.0.0.1:3240".into());
+	if let Err(e) = usbip::serve(&addr) {
+		println!("usbip: {}", e);
+		::std::process::exit(1);
+	}
+}
+
+#[cfg(not(feature = "usbip"))]
 #[allow(unused_variables)]
 #[inline(never)]
 fn main(hw: board::Hardware) -> ! {
@@ -104,12 +125,93 @@ fn main(hw: board::Hardware) -> ! {
 
 	// init sdram (needed for display buffer)
 	sdram::init(rcc, fmc, &mut gpio);
-	let lcd = lcd::init(ltdc, rcc, &mut gpio);
+	let mut lcd = lcd::init(ltdc, rcc, &mut gpio);
+	lcd.clear_screen();
 
-	unsafe { usb::interrupt::init_debug(lcd); }
-	let usb = usb::init::init(rcc, &mut gpio, otg_hs_global, otg_hs_device, nvic);
+	// the touch controller of the LCD
+	i2c::init_pins_and_clocks(rcc, &mut gpio);
+	let mut i2c_3 = i2c::init(i2c_3);
+	// keep running without usb if the ULPI pins are taken, the error code goes on the LCD
+	let mut usb = match usb::init::init(rcc, &mut gpio, otg_hs_global, otg_hs_device, nvic,
+		usb::driver::Mode::Slave) {
+		Ok(usb) => Some(usb),
+		Err(e) => {
+			render::render_number(e.code() as i64, 16, render::TEST_DIM, (10, 40), 2, &mut lcd);
+			None
+		},
+	};
+	let mut tracker = usb::digitizer::Tracker::new();
 	
 	loop {
-		
+		if let Some(ref mut usb) = usb {
+			echo(&mut usb.serial());
+			touch_screen(&mut usb.digitizer(), &mut tracker, &mut i2c_3);
+		}
+	}
+}
+
+// Echoes what is typed into the terminal on the USB serial port, return starts a new line.
+#[cfg(not(feature = "usbip"))]
+fn echo(serial: &mut usb::serial::Serial) {
+	use core::fmt::Write;
+
+	let mut buf = [0u8; 64];
+	let count = serial.read(&mut buf);
+	for byte in &buf[..count] {
+		let _ = match *byte {
+			b'\r' => serial.write_str("\r\n"),
+			byte if byte < 0x80 => serial.write_char(byte as char),
+			_ => Ok(()),
+		};
 	}
 }
+
+// Reports the touches on the LCD to the host, a report that is not taken yet is tried
+// again with the touches of the next round.
+#[cfg(not(feature = "usbip"))]
+fn touch_screen(digitizer: &mut usb::digitizer::Digitizer, tracker: &mut usb::digitizer::Tracker,
+		i2c_3: &mut i2c::I2C) {
+	let (points, count) = match touch_points(i2c_3) {
+		Ok(points) => points,
+		Err(_) => return,
+	};
+	if let Some(report) = tracker.report(points[..count].iter().cloned()) {
+		if digitizer.send(&report) {
+			tracker.sent(&report);
+		}
+	}
+}
+
+// FT5336 touch controller of the LCD
+#[cfg(not(feature = "usbip"))]
+const FT5336_ADDRESS : u8 = 0x38;
+// number of touches in the low nibble
+#[cfg(not(feature = "usbip"))]
+const FT5336_STATUS : u8 = 0x02;
+// XH, XL, YH, YL of each touch, the touch ID is in the high nibble of YH
+#[cfg(not(feature = "usbip"))]
+const FT5336_TOUCHES : [u8; usb::digitizer::MAX_CONTACTS] = [0x03, 0x09, 0x0f, 0x15, 0x1b];
+
+// The touches on the LCD as (touch ID, x, y) in pixels, the first `count` are valid.
+#[cfg(not(feature = "usbip"))]
+fn touch_points(i2c_3: &mut i2c::I2C)
+		-> Result<([(u8, u16, u16); usb::digitizer::MAX_CONTACTS], usize), i2c::Error> {
+	let mut points = [(0, 0, 0); usb::digitizer::MAX_CONTACTS];
+	let mut count = 0;
+	try!(i2c_3.connect::<u8, _>(i2c::Address::bits_7(FT5336_ADDRESS), |mut conn| {
+		let status = try!(conn.read(FT5336_STATUS)) & 0x0f;
+		for &register in FT5336_TOUCHES.iter().take(status as usize) {
+			let mut data = [0u8; 4];
+			for (i, byte) in data.iter_mut().enumerate() {
+				*byte = try!(conn.read(register + i as u8));
+			}
+			// x and y of the controller are swapped on the LCD
+			let y = ((data[0] & 0x0f) as u16) << 8 | data[1] as u16;
+			let x = ((data[2] & 0x0f) as u16) << 8 | data[3] as u16;
+			points[count] = (data[2] >> 4, x, y);
+			count += 1;
+		}
+		Ok(())
+	}));
+	Ok((points, count))
+}
@@ -75,62 +75,3 @@ pub fn render_number_color(mut num: i64, mut base: u32, dim: (u16, u16), mut off
 	}
 
 }
-
-
-pub fn interrupt_debug(gintsts: u32, gintsts_triggered: u32, count: &mut u32, last_row: &mut u16, last_mask: &mut u32, lcd: &mut lcd::Lcd) {
-	//COUNT
-	render_number(*count as i64, 10, TEST_DIM, (0, 400), 4, lcd);
-	*count += 1;
-
-	//TRIGGERED
-	render_number(gintsts_triggered as i64, 2, TEST_DIM, (30, 400), 32, lcd);
-	//GINTSTS
-	render_number(gintsts as i64, 2, TEST_DIM, (100, 400), 32, lcd);
-	let mut row = 0;
-
-	//IRQ Column
-	for i in 0i64..32 {
-		if (gintsts & (1<<i)) != 0 {
-			let color = if (*last_mask & gintsts) & (1<<i) != 0 {0xffffu16} 
-				else {0xf0f0u16};
-			render_number_color(i, 10, TEST_DIM, 
-				(15+row*15, 460), 2, color, lcd);
-			row += 1;
-		}
-	}
-	//CLEAR IRQ Column Remainder Efficient
-	for i in row..*last_row {
-		for x in 0..(9*TEST_DIM.1)/4+1 {
-			for y in 0..TEST_DIM.0 {
-				lcd.print_point_color_at(460-x, 15+i*15+y, 0);
-			}
-		}
-	}
-
-	//IRQ Triggered Column
-	let mut row_2 = 0;
-	for i in 0i64..32 {
-		if (gintsts_triggered & (1<<i)) != 0 {
-			let color = if gintsts & (1<<i) == 0 {0xffffu16} 
-				else {0xf0f0u16};
-			render_number_color(i, 10, TEST_DIM, 
-				(15+row_2*15, 50), 2, color, lcd);
-			row_2 += 1;
-		}
-	}
-	*last_row = row;
-	*last_mask = gintsts;
-}
-
-pub fn interrupt_debug_init(lcd: &mut lcd::Lcd) {
-	lcd.clear_screen();
-	lcd.set_background_color(lcd::Color::rgb(0,0,0));
-	for i in 0..32 {
-		let j = i % 10;
-		let k = i / 10;
-		if j == 0 {
-			render_number(k as i64, 10, TEST_DIM, (65, 400-(i*5*TEST_DIM.1)/4), 1, lcd);
-		}
-		render_number(j as i64, 10, TEST_DIM, (80, 400-(i*5*TEST_DIM.1)/4), 1, lcd);
-	}
-}
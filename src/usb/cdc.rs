@@ -0,0 +1,137 @@
+// CDC-ACM virtual serial port (USB CDC 1.2 and PSTN 1.2). The communication interface
+// takes the class requests on endpoint 0 and owns the notification endpoint, the data
+// interface has the bulk endpoint pair the serial data goes over.
+
+use super::control::{Reply, Setup};
+
+// interface numbers and endpoint addresses in `descriptor::TREE`
+pub const COMM_INTERFACE : u8 = 0;
+pub const DATA_INTERFACE : u8 = 1;
+pub const NOTIFY_EP : u8 = 0x82;
+pub const DATA_OUT_EP : u8 = 0x01;
+pub const DATA_IN_EP : u8 = 0x81;
+// max packet size of the data endpoints
+pub const DATA_MPS : u16 = 512;
+
+// bInterfaceClass, bInterfaceSubClass and bInterfaceProtocol
+pub const CLASS_COMM : u8 = 0x02;
+pub const SUBCLASS_ACM : u8 = 0x02;
+pub const PROTO_AT : u8 = 0x01;
+pub const CLASS_DATA : u8 = 0x0a;
+
+// functional descriptors, bDescriptorType CS_INTERFACE and everything after it
+pub const CS_INTERFACE : u8 = 0x24;
+// CDC 1.10
+pub const HEADER : &'static [u8] = &[0x00, 0x10, 0x01];
+// the device does no call management
+pub const CALL_MANAGEMENT : &'static [u8] = &[0x01, 0x00, DATA_INTERFACE];
+// SET_LINE_CODING, GET_LINE_CODING and SET_CONTROL_LINE_STATE
+pub const ACM : &'static [u8] = &[0x02, 0x02];
+pub const UNION : &'static [u8] = &[0x06, COMM_INTERFACE, DATA_INTERFACE];
+
+// bRequest
+pub const SET_LINE_CODING : u8 = 0x20;
+pub const GET_LINE_CODING : u8 = 0x21;
+pub const SET_CONTROL_LINE_STATE : u8 = 0x22;
+
+// wValue of SET_CONTROL_LINE_STATE
+const DTR : u16 = 0x1;
+const RTS : u16 = 0x2;
+
+const LINE_CODING_LEN : usize = 7;
+
+// Line coding the host set. The port only exists over USB, so it has no effect on the
+// data but is kept for GET_LINE_CODING.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LineCoding {
+	pub rate: u32,
+	// 0 is 1 stop bit, 1 is 1.5 and 2 is 2
+	pub stop_bits: u8,
+	// none, odd, even, mark, space
+	pub parity: u8,
+	pub data_bits: u8,
+}
+
+impl LineCoding {
+	fn parse(data: &[u8]) -> LineCoding {
+		LineCoding {
+			rate: data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24,
+			stop_bits: data[4],
+			parity: data[5],
+			data_bits: data[6],
+		}
+	}
+
+	fn write(&self, buf: &mut [u8]) {
+		for i in 0..4 {
+			buf[i] = (self.rate >> (i*8)) as u8;
+		}
+		buf[4] = self.stop_bits;
+		buf[5] = self.parity;
+		buf[6] = self.data_bits;
+	}
+}
+
+// 115200 8N1
+const DEFAULT_LINE_CODING : LineCoding = LineCoding { rate: 115200, stop_bits: 0, parity: 0, data_bits: 8 };
+
+pub struct Acm {
+	line_coding: LineCoding,
+	control_line_state: u16,
+}
+
+impl Acm {
+	pub fn new() -> Acm {
+		Acm {
+			line_coding: DEFAULT_LINE_CODING,
+			control_line_state: 0,
+		}
+	}
+
+	// bus reset or new configuration, the port is closed
+	pub fn reset(&mut self) {
+		*self = Acm::new();
+	}
+
+	pub fn line_coding(&self) -> LineCoding {
+		self.line_coding
+	}
+
+	// the host has the port open
+	pub fn dtr(&self) -> bool {
+		self.control_line_state & DTR != 0
+	}
+
+	pub fn rts(&self) -> bool {
+		self.control_line_state & RTS != 0
+	}
+
+	// Class requests to the communication interface.
+	pub fn request(&mut self, setup: &Setup, buf: &mut [u8]) -> Reply {
+		match setup.request {
+			// the line coding follows in the data stage
+			SET_LINE_CODING if !setup.is_in() && setup.length as usize == LINE_CODING_LEN => Reply::Ack,
+			GET_LINE_CODING if setup.is_in() => {
+				self.line_coding.write(buf);
+				Reply::Data(LINE_CODING_LEN)
+			},
+			SET_CONTROL_LINE_STATE if setup.length == 0 => {
+				self.control_line_state = setup.value;
+				Reply::Ack
+			},
+			// SEND_BREAK and the rest are not supported
+			_ => Reply::Stall,
+		}
+	}
+
+	// Data stage of a class request `request` accepted.
+	pub fn data_out(&mut self, setup: &Setup, data: &[u8]) -> Reply {
+		match setup.request {
+			SET_LINE_CODING if data.len() == LINE_CODING_LEN => {
+				self.line_coding = LineCoding::parse(data);
+				Reply::Ack
+			},
+			_ => Reply::Stall,
+		}
+	}
+}
@@ -0,0 +1,264 @@
+// Control transfers on endpoint 0. The interrupt handlers feed the events of the core
+// in, `Control` tracks the stage of the transfer and the data of its data stage.
+
+use super::descriptor;
+
+// data stage of a request, the longest is the report descriptor of the digitizer
+pub const BUF_LEN : usize = 512;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Setup {
+	pub request_type: u8,
+	pub request: u8,
+	pub value: u16,
+	pub index: u16,
+	pub length: u16,
+}
+
+impl Setup {
+	pub fn parse(data: &[u8]) -> Setup {
+		Setup {
+			request_type: data[0],
+			request: data[1],
+			value: ((data[3] as u16) << 8u16) | data[2] as u16,
+			index: ((data[5] as u16) << 8u16) | data[4] as u16,
+			length: ((data[7] as u16) << 8u16) | data[6] as u16,
+		}
+	}
+
+	// device-to-host
+	pub fn is_in(&self) -> bool {
+		self.request_type & 0x80 != 0
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Stage {
+	Idle,
+	Setup,
+	DataIn,
+	DataOut,
+	// all OUT data received, the request handler has to accept it
+	DataOutDone,
+	StatusIn,
+	StatusOut,
+	Stall,
+}
+
+// Answer of the request handler to a SETUP
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Reply {
+	// IN data stage with the first n bytes of the buffer
+	Data(usize),
+	// request accepted, continue with the OUT data or the status stage
+	Ack,
+	Stall,
+}
+
+pub struct Control {
+	stage: Stage,
+	setup: Option<Setup>,
+	// latest SETUP popped from the RX FIFO, back-to-back SETUPs overwrite it
+	received: Option<Setup>,
+	// in words so that the DMA can work on it
+	buf: [u32; BUF_LEN / 4],
+	len: usize,
+	// IN data stage: bytes already sent and whether a zero length packet has to end it
+	sent: usize,
+	zlp: bool,
+	// OUT data stage: the last packet was short and ended it
+	short: bool,
+	mps: usize,
+}
+
+impl Control {
+	pub fn new() -> Control {
+		Control {
+			stage: Stage::Idle,
+			setup: None,
+			received: None,
+			buf: [0; BUF_LEN / 4],
+			len: 0,
+			sent: 0,
+			zlp: false,
+			short: false,
+			mps: descriptor::DEVICE_DESCRIPTOR.mps as usize,
+		}
+	}
+
+	pub fn stage(&self) -> Stage {
+		self.stage
+	}
+
+	pub fn setup(&self) -> Option<Setup> {
+		self.setup
+	}
+
+	// data of the current data stage
+	pub fn data(&self) -> &[u8] {
+		&self.bytes()[..self.len]
+	}
+
+	// buffer the request handler writes the data of an IN data stage to
+	pub fn buf(&mut self) -> &mut [u8] {
+		unsafe { ::core::slice::from_raw_parts_mut(self.buf.as_mut_ptr() as *mut u8, BUF_LEN) }
+	}
+
+	fn bytes(&self) -> &[u8] {
+		unsafe { ::core::slice::from_raw_parts(self.buf.as_ptr() as *const u8, BUF_LEN) }
+	}
+
+	// Next IN packet of the data or status stage.
+	pub fn in_packet(&self) -> &[u8] {
+		match self.stage {
+			Stage::DataIn => {
+				let end = ::core::cmp::min(self.sent + self.mps, self.len);
+				&self.bytes()[self.sent..end]
+			},
+			_ => &[],
+		}
+	}
+
+	pub fn reset(&mut self) {
+		self.stage = Stage::Idle;
+		self.setup = None;
+		self.received = None;
+		self.len = 0;
+		self.sent = 0;
+		self.zlp = false;
+		self.short = false;
+	}
+
+	pub fn stall(&mut self) {
+		self.stage = Stage::Stall;
+	}
+
+	// SETUP data packet read from the RX FIFO
+	pub fn setup_received(&mut self, data: &[u8]) {
+		self.received = Some(Setup::parse(data));
+	}
+
+	// The SETUP stage is over (DOEPINT0.STUP). Whatever transfer was still going on has
+	// been aborted by the host, returns the request to answer.
+	pub fn setup_done(&mut self) -> Option<Setup> {
+		self.setup = self.received.take();
+		self.len = 0;
+		self.stage = match self.setup {
+			Some(_) => Stage::Setup,
+			None => Stage::Idle,
+		};
+		self.setup
+	}
+
+	// Answer of the request handler to the SETUP or to the data of the OUT data stage.
+	pub fn reply(&mut self, reply: Reply) -> Stage {
+		let setup = match self.setup {
+			Some(setup) => setup,
+			None => return self.stage,
+		};
+		if self.stage == Stage::DataOutDone {
+			self.stage = match reply {
+				Reply::Ack => Stage::StatusIn,
+				Reply::Data(_) | Reply::Stall => Stage::Stall,
+			};
+			return self.stage;
+		}
+		if self.stage != Stage::Setup {
+			return self.stage;
+		}
+		self.stage = match reply {
+			Reply::Stall => Stage::Stall,
+			Reply::Data(len) if setup.is_in() && len <= BUF_LEN => {
+				self.start_in(::core::cmp::min(len, setup.length as usize), setup.length as usize);
+				Stage::DataIn
+			},
+			Reply::Data(_) => Stage::Stall,
+			Reply::Ack if setup.length == 0 => Stage::StatusIn,
+			Reply::Ack if setup.is_in() => {
+				self.start_in(0, setup.length as usize);
+				Stage::DataIn
+			},
+			Reply::Ack if setup.length as usize > BUF_LEN => Stage::Stall,
+			Reply::Ack => {
+				self.len = 0;
+				self.short = false;
+				Stage::DataOut
+			},
+		};
+		self.stage
+	}
+
+	// A data stage shorter than wLength ends with a short packet, which is a zero length
+	// packet if the data is a multiple of the max packet size.
+	fn start_in(&mut self, len: usize, length: usize) {
+		self.len = len;
+		self.sent = 0;
+		self.zlp = len < length && len % self.mps == 0;
+	}
+
+	// IN packet on endpoint 0 sent (DIEPINT0.XFRC)
+	pub fn in_done(&mut self) -> Stage {
+		if self.stage == Stage::DataIn {
+			let packet = self.in_packet().len();
+			self.sent += packet;
+			if packet == 0 {
+				self.zlp = false;
+			}
+			if self.sent < self.len || self.zlp {
+				return self.stage;
+			}
+		}
+		self.stage = match self.stage {
+			Stage::DataIn => Stage::StatusOut,
+			Stage::StatusIn => Stage::Idle,
+			stage => stage,
+		};
+		self.stage
+	}
+
+	// bytes the host sends in the data stage, wLength
+	fn expected(&self) -> usize {
+		self.setup.map_or(0, |setup| ::core::cmp::min(setup.length as usize, BUF_LEN))
+	}
+
+	// Size of the next OUT packet of the data stage.
+	pub fn out_packet_len(&self) -> usize {
+		::core::cmp::min(self.mps, self.expected() - self.len)
+	}
+
+	// OUT data packet read from the RX FIFO
+	pub fn out_received(&mut self, data: &[u8]) {
+		if self.stage == Stage::DataOut {
+			let (start, len) = (self.len, ::core::cmp::min(data.len(), self.expected() - self.len));
+			self.buf()[start..start + len].copy_from_slice(&data[..len]);
+			self.len += len;
+			self.short = data.len() < self.mps;
+		}
+	}
+
+	// where the DMA puts the next OUT packet of the data stage
+	pub fn out_buf(&mut self) -> &mut [u8] {
+		let start = self.len;
+		&mut self.buf()[start..]
+	}
+
+	// OUT data packet of `count` bytes the DMA wrote to `out_buf`
+	pub fn out_written(&mut self, count: usize) {
+		if self.stage == Stage::DataOut {
+			self.len += ::core::cmp::min(count, self.expected() - self.len);
+			self.short = count < self.mps;
+		}
+	}
+
+	// OUT packet on endpoint 0 received (DOEPINT0.XFRC). The data stage is over after
+	// wLength bytes or a short packet.
+	pub fn out_done(&mut self) -> Stage {
+		self.stage = match self.stage {
+			Stage::DataOut if self.len < self.expected() && !self.short => Stage::DataOut,
+			Stage::DataOut => Stage::DataOutDone,
+			Stage::StatusOut => Stage::Idle,
+			stage => stage,
+		};
+		self.stage
+	}
+}
@@ -0,0 +1,876 @@
+// Descriptors the device reports to the host.
+
+use super::cdc;
+use super::hid::{self, ReportDescriptor};
+use super::keyboard;
+use super::digitizer;
+
+// bDescriptorType
+pub const DEVICE : u8 = 1;
+pub const CONFIGURATION : u8 = 2;
+pub const STRING : u8 = 3;
+pub const INTERFACE : u8 = 4;
+pub const ENDPOINT : u8 = 5;
+pub const DEVICE_QUALIFIER : u8 = 6;
+pub const OTHER_SPEED_CONFIGURATION : u8 = 7;
+pub const INTERFACE_ASSOCIATION : u8 = 11;
+
+// bDeviceClass, bDeviceSubClass and bDeviceProtocol of a device whose functions are
+// grouped with interface association descriptors
+pub const CLASS_MISC : u8 = 0xef;
+pub const SUBCLASS_COMMON : u8 = 0x02;
+pub const PROTO_IAD : u8 = 0x01;
+
+// string descriptor indices
+pub const STR_MANUFACTURER : u8 = 1;
+pub const STR_PRODUCT : u8 = 2;
+pub const STR_SERIAL : u8 = 3;
+pub const STR_CONFIGURATION : u8 = 4;
+pub const STR_INTERFACE : u8 = 5;
+pub const STR_KEYBOARD : u8 = 6;
+pub const STR_TOUCH_SCREEN : u8 = 7;
+
+// the serial number is the unique device ID in hex
+pub const SERIAL_LEN : usize = 24;
+
+// supported LANGIDs, reported as string descriptor 0
+pub const LANGIDS : &'static [u16] = &[
+	0x0409, // English (United States)
+];
+
+// strings by index, entry 0 stands for the LANGID table
+pub const NUM_STRINGS : usize = 8;
+pub const STRINGS : [&'static str; NUM_STRINGS] = [
+	"",
+	"Rust-Mikrocontroller-Praktikum-2017",
+	"STM32F7 Discovery USB",
+	"", // serial number, generated at runtime by `serial_number`
+	"Default configuration",
+	"Serial port",
+	"Keyboard",
+	"Touch screen",
+];
+
+// bmAttributes of the configuration: reserved bit 7 and self powered
+const CONFIG_ATTRIBUTES : u8 = 0x80 | 0x40;
+// bmAttributes of an endpoint: transfer type
+pub const CONTROL : u8 = 0x0;
+pub const ISOCHRONOUS : u8 = 0x1;
+pub const BULK : u8 = 0x2;
+pub const INTERRUPT : u8 = 0x3;
+// bMaxPower in units of 2 mA
+const CONFIG_MAX_POWER : u8 = 50;
+
+pub struct DeviceDescriptor {
+	pub bcd_usb: u16,
+	pub class: u8,
+	pub subclass: u8,
+	pub proto: u8,
+	pub mps: u8,
+	pub vendor: u16,
+	pub product: u16,
+	pub bcd_device: u16,
+	pub ivendor: u8,
+	pub iproduct: u8,
+	pub iserial: u8,
+	pub numconfig: u8,
+}
+
+// Entries of a configuration tree. bLength, wTotalLength and bNumInterfaces are left
+// out, `Builder` fills them in.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Descriptor {
+	Configuration { value: u8, iconfiguration: u8, attributes: u8, max_power: u8 },
+	// interface association, groups `count` interfaces from `first` into one function
+	Association { first: u8, count: u8, class: u8, subclass: u8, proto: u8, ifunction: u8 },
+	Interface { number: u8, alternate: u8, endpoints: u8, class: u8, subclass: u8, proto: u8, iinterface: u8 },
+	Endpoint { address: u8, attributes: u8, mps: u16, interval: u8 },
+	// class-specific descriptor, bDescriptorType and everything after it
+	Class { desc_type: u8, data: &'static [u8] },
+	// HID descriptor, wDescriptorLength is the length of the report descriptor `report`
+	// writes
+	Hid { country: u8, report: ReportDescriptor },
+}
+
+// Compile time checks of `descriptors!`. Every check is a constant of its own whose type
+// only matches while the condition holds, so a failing check is a type error (E0308,
+// "mismatched types") in `descriptor_check` whether the function around it is used or not.
+macro_rules! descriptor_check {
+	($cond:expr) => { { const DESCRIPTOR_CHECK : [(); 1] = [(); ($cond) as usize]; } }
+}
+
+macro_rules! descriptor_one {
+	($x:expr) => { 1 }
+}
+
+macro_rules! descriptor_entry {
+	(endpoint { address: $address:expr, attributes: $attributes:expr, mps: $mps:expr, interval: $interval:expr $(,)* }) => {
+		$crate::usb::descriptor::Descriptor::Endpoint { address: $address, attributes: $attributes, mps: $mps, interval: $interval }
+	};
+	(class { desc_type: $desc_type:expr, data: $data:expr $(,)* }) => {
+		$crate::usb::descriptor::Descriptor::Class { desc_type: $desc_type, data: $data }
+	};
+	(hid { country: $country:expr, report: $report:expr $(,)* }) => {
+		$crate::usb::descriptor::Descriptor::Hid { country: $country,
+			report: $crate::usb::hid::ReportDescriptor($report) }
+	};
+}
+
+macro_rules! descriptor_check_entry {
+	(endpoint { address: $address:expr, attributes: $attributes:expr, mps: $mps:expr, interval: $interval:expr $(,)* }) => {
+		// endpoints 1 to 7, endpoint 0 is the control endpoint
+		descriptor_check!(($address & 0x70) == 0 && ($address & 0x0f) != 0 && ($address & 0x0f) < 8);
+		// bulk endpoints only have 512 byte packets at high speed
+		descriptor_check!(($attributes & 0x3) != $crate::usb::descriptor::BULK || $mps == 512);
+		descriptor_check!($mps <= 1024);
+	};
+	(class { $($entry:tt)* }) => {};
+	(hid { $($entry:tt)* }) => {};
+}
+
+macro_rules! descriptor_count_endpoints {
+	() => { 0 };
+	(endpoint { $($entry:tt)* } $($rest:tt)*) => { 1 + descriptor_count_endpoints!($($rest)*) };
+	($kind:ident { $($entry:tt)* } $($rest:tt)*) => { descriptor_count_endpoints!($($rest)*) };
+}
+
+// Collects the endpoint addresses into the list in front and checks that none of them
+// is used twice.
+macro_rules! descriptor_distinct_endpoints {
+	([$($address:expr),*] endpoint { address: $next:expr, $($entry:tt)* } $($rest:tt)*) => {
+		descriptor_distinct_endpoints!([$($address,)* $next] $($rest)*);
+	};
+	([$($address:expr),*] $kind:ident { $($entry:tt)* } $($rest:tt)*) => {
+		descriptor_distinct_endpoints!([$($address),*] $($rest)*);
+	};
+	([]) => {};
+	([$first:expr $(, $address:expr)*]) => {
+		$( descriptor_check!($first != $address); )*
+		descriptor_distinct_endpoints!([$($address),*]);
+	};
+}
+
+// Declares the device descriptor `$dev` and the configuration tree `$tree` and checks
+// them at compile time:
+// - bMaxPacketSize0 is 8, 16, 32 or 64
+// - bNumInterfaces and bNumEndpoints match the interfaces and endpoints declared
+// - endpoint numbers are 1 to 7, the OTG HS core has 8 endpoints per direction
+// - no endpoint address is used twice
+// - bulk endpoints have a max packet size of 512
+// - there are no more HID interfaces than `hid::MAX_FUNCTIONS`
+// - string indices are below `strings`
+// - an interface association comes right before its first interface
+// There is one configuration and every interface only has alternate setting 0. The
+// checks end up in a function that is never called, so use it at most once per module.
+macro_rules! descriptors {
+	(
+		device $dev:ident {
+			bcd_usb: $bcd_usb:expr, class: $class:expr, subclass: $subclass:expr, proto: $proto:expr,
+			mps: $mps:expr, vendor: $vendor:expr, product: $product:expr, bcd_device: $bcd_device:expr,
+			ivendor: $ivendor:expr, iproduct: $iproduct:expr, iserial: $iserial:expr,
+			numconfig: $numconfig:expr $(,)*
+		}
+		strings $strings:expr;
+		tree $tree:ident {
+			configuration {
+				value: $value:expr, interfaces: $interfaces:expr, iconfiguration: $iconfiguration:expr,
+				attributes: $attributes:expr, max_power: $max_power:expr $(,)*
+			}
+			$(
+				$(
+					association {
+						first: $first:expr, count: $count:expr, class: $fn_class:expr,
+						subclass: $fn_subclass:expr, proto: $fn_proto:expr, ifunction: $ifunction:expr $(,)*
+					}
+				)*
+				interface {
+					number: $number:expr, endpoints: $endpoints:expr, class: $if_class:expr,
+					subclass: $if_subclass:expr, proto: $if_proto:expr, iinterface: $iinterface:expr $(,)*
+				} [ $( $kind:ident { $($entry:tt)* } )* ]
+			)*
+		}
+	) => {
+		pub const $dev : $crate::usb::descriptor::DeviceDescriptor = $crate::usb::descriptor::DeviceDescriptor {
+			bcd_usb: $bcd_usb,
+			class: $class,
+			subclass: $subclass,
+			proto: $proto,
+			mps: $mps,
+			vendor: $vendor,
+			product: $product,
+			bcd_device: $bcd_device,
+			ivendor: $ivendor,
+			iproduct: $iproduct,
+			iserial: $iserial,
+			numconfig: $numconfig,
+		};
+
+		pub const $tree : &'static [$crate::usb::descriptor::Descriptor] = &[
+			$crate::usb::descriptor::Descriptor::Configuration { value: $value,
+				iconfiguration: $iconfiguration, attributes: $attributes, max_power: $max_power },
+			$(
+				$(
+					$crate::usb::descriptor::Descriptor::Association { first: $first, count: $count,
+						class: $fn_class, subclass: $fn_subclass, proto: $fn_proto, ifunction: $ifunction },
+				)*
+				$crate::usb::descriptor::Descriptor::Interface { number: $number, alternate: 0,
+					endpoints: $endpoints, class: $if_class, subclass: $if_subclass, proto: $if_proto,
+					iinterface: $iinterface },
+				$( descriptor_entry!($kind { $($entry)* }), )*
+			)*
+		];
+
+		#[allow(dead_code)]
+		fn check_descriptors() {
+			descriptor_check!($mps == 8 || $mps == 16 || $mps == 32 || $mps == 64);
+			descriptor_check!($numconfig == 1);
+			descriptor_check!(($ivendor as usize) < $strings);
+			descriptor_check!(($iproduct as usize) < $strings);
+			descriptor_check!(($iserial as usize) < $strings);
+			descriptor_check!($value != 0);
+			descriptor_check!($interfaces == 0 $(+ descriptor_one!($number))*);
+			descriptor_check!(0 $(+ ($if_class == $crate::usb::hid::CLASS_HID) as usize)*
+				<= $crate::usb::hid::MAX_FUNCTIONS);
+			descriptor_check!(($iconfiguration as usize) < $strings);
+			$(
+				descriptor_check!($endpoints == descriptor_count_endpoints!($( $kind { $($entry)* } )*));
+				descriptor_check!(($iinterface as usize) < $strings);
+				$(
+					// right in front of the first interface of the function
+					descriptor_check!($first == $number && $count >= 1);
+					descriptor_check!(($ifunction as usize) < $strings);
+				)*
+				$( descriptor_check_entry!($kind { $($entry)* }); )*
+			)*
+			descriptor_distinct_endpoints!([] $( $( $kind { $($entry)* } )* )*);
+		}
+	}
+}
+
+descriptors! {
+	device DEVICE_DESCRIPTOR {
+		bcd_usb: 0x0200,
+		// the serial port and the HID interfaces are separate functions
+		class: CLASS_MISC,
+		subclass: SUBCLASS_COMMON,
+		proto: PROTO_IAD,
+		mps: 64,
+		vendor: 0x3412,
+		product: 0x7856,
+		bcd_device: 0x5713,
+		ivendor: STR_MANUFACTURER,
+		iproduct: STR_PRODUCT,
+		iserial: STR_SERIAL,
+		numconfig: 1, //num configuration descriptors
+	}
+	strings NUM_STRINGS;
+	// The configuration tree, one descriptor per entry in the order the host gets them.
+	tree TREE {
+		configuration { value: 1, interfaces: 4, iconfiguration: STR_CONFIGURATION,
+			attributes: CONFIG_ATTRIBUTES, max_power: CONFIG_MAX_POWER }
+		// CDC-ACM communication interface with its functional descriptors, the association
+		// tells the host that the data interface belongs to it
+		association { first: cdc::COMM_INTERFACE, count: 2, class: cdc::CLASS_COMM,
+			subclass: cdc::SUBCLASS_ACM, proto: cdc::PROTO_AT, ifunction: STR_INTERFACE }
+		interface { number: cdc::COMM_INTERFACE, endpoints: 1, class: cdc::CLASS_COMM,
+			subclass: cdc::SUBCLASS_ACM, proto: cdc::PROTO_AT, iinterface: STR_INTERFACE } [
+			class { desc_type: cdc::CS_INTERFACE, data: cdc::HEADER }
+			class { desc_type: cdc::CS_INTERFACE, data: cdc::CALL_MANAGEMENT }
+			class { desc_type: cdc::CS_INTERFACE, data: cdc::ACM }
+			class { desc_type: cdc::CS_INTERFACE, data: cdc::UNION }
+			// serial state notifications, every 32 ms (2^(9-1) microframes)
+			endpoint { address: cdc::NOTIFY_EP, attributes: INTERRUPT, mps: 16, interval: 9 }
+		]
+		// CDC data interface
+		interface { number: cdc::DATA_INTERFACE, endpoints: 2, class: cdc::CLASS_DATA,
+			subclass: 0x00, proto: 0x00, iinterface: STR_INTERFACE } [
+			endpoint { address: cdc::DATA_OUT_EP, attributes: BULK, mps: cdc::DATA_MPS, interval: 0 }
+			endpoint { address: cdc::DATA_IN_EP, attributes: BULK, mps: cdc::DATA_MPS, interval: 0 }
+		]
+		// boot protocol keyboard, the LEDs come with SET_REPORT
+		interface { number: keyboard::INTERFACE, endpoints: 1, class: hid::CLASS_HID,
+			subclass: hid::SUBCLASS_BOOT, proto: hid::PROTO_KEYBOARD, iinterface: STR_KEYBOARD } [
+			hid { country: 0, report: keyboard::report }
+			endpoint { address: keyboard::IN_EP, attributes: INTERRUPT, mps: keyboard::REPORT_LEN as u16,
+				interval: keyboard::INTERVAL }
+		]
+		// multi-touch digitizer for the touch panel of the LCD
+		interface { number: digitizer::INTERFACE, endpoints: 1, class: hid::CLASS_HID,
+			subclass: hid::SUBCLASS_NONE, proto: hid::PROTO_NONE, iinterface: STR_TOUCH_SCREEN } [
+			hid { country: 0, report: digitizer::report }
+			endpoint { address: digitizer::IN_EP, attributes: INTERRUPT, mps: digitizer::REPORT_LEN as u16,
+				interval: digitizer::INTERVAL }
+		]
+	}
+}
+
+// Writes descriptor `index` of type `desc_type` to `buf` and returns its full length,
+// None if there is no such descriptor or it does not fit.
+pub fn get(desc_type: u8, index: u8, buf: &mut [u8]) -> Option<usize> {
+	match (desc_type, index) {
+		(DEVICE, 0) => Builder::new(buf).device(&DEVICE_DESCRIPTOR).finish(),
+		(CONFIGURATION, 0) => configuration(TREE, buf),
+		// the device runs at high speed, these describe it at full speed
+		(DEVICE_QUALIFIER, 0) => Builder::new(buf).qualifier(&DEVICE_DESCRIPTOR).finish(),
+		(OTHER_SPEED_CONFIGURATION, 0) => other_speed_configuration(TREE, buf),
+		(STRING, 0) => Builder::new(buf).langids(LANGIDS).finish(),
+		(STRING, i) if (i as usize) < STRINGS.len() => Builder::new(buf).string(STRINGS[i as usize]).finish(),
+		_ => None,
+	}
+}
+
+// Serial number string of the chip with unique device ID `uid`, most significant digit first.
+pub fn serial_number(uid: [u32; 3]) -> [u8; SERIAL_LEN] {
+	const HEX : &'static [u8; 16] = b"0123456789ABCDEF";
+	let mut serial = [0; SERIAL_LEN];
+	for (i, digit) in serial.iter_mut().enumerate() {
+		let word = uid[2 - i / 8];
+		*digit = HEX[((word >> (28 - 4 * (i % 8))) & 0xf) as usize];
+	}
+	serial
+}
+
+// Writes the configuration descriptor `tree` with all of its interface, endpoint and
+// class-specific descriptors to `buf`.
+pub fn configuration(tree: &[Descriptor], buf: &mut [u8]) -> Option<usize> {
+	let mut builder = Builder::new(buf);
+	for desc in tree {
+		builder.push(desc);
+	}
+	builder.finish()
+}
+
+// bConfigurationValue of the configuration tree `tree`.
+pub fn configuration_value(tree: &[Descriptor]) -> Option<u8> {
+	tree.iter().filter_map(|desc| match *desc {
+		Descriptor::Configuration { value, .. } => Some(value),
+		_ => None,
+	}).next()
+}
+
+// Writes the configuration descriptor `tree` the way it would be at full speed: bulk
+// endpoints have 64 byte packets, interrupt endpoints at most 64 and the intervals are
+// in frames instead of microframes.
+pub fn other_speed_configuration(tree: &[Descriptor], buf: &mut [u8]) -> Option<usize> {
+	let len = {
+		let mut builder = Builder::new(buf);
+		for desc in tree {
+			builder.push(&full_speed(desc));
+		}
+		builder.finish()
+	};
+	if len.is_some() {
+		buf[1] = OTHER_SPEED_CONFIGURATION;
+	}
+	len
+}
+
+fn full_speed(desc: &Descriptor) -> Descriptor {
+	match *desc {
+		Descriptor::Endpoint { address, attributes, mps, interval } => {
+			// high speed intervals are 2^(interval-1) microframes
+			let exponent = ::core::cmp::max(interval, 1) as u32 - 1;
+			let (mps, interval) = match attributes & 0x3 {
+				BULK => (64, 0),
+				INTERRUPT => {
+					let frames = ::core::cmp::min(::core::cmp::max((1 << exponent) / 8, 1), 255);
+					(::core::cmp::min(mps & 0x7ff, 64), frames as u8)
+				},
+				// 2^(interval-1) frames at full speed
+				ISOCHRONOUS => (::core::cmp::min(mps & 0x7ff, 1023), ::core::cmp::max(exponent, 3) as u8 - 2),
+				_ => (mps, interval),
+			};
+			Descriptor::Endpoint { address: address, attributes: attributes, mps: mps, interval: interval }
+		},
+		_ => *desc,
+	}
+}
+
+// Writes the descriptor of type `desc_type` that belongs to interface `number` of `tree`
+// to `buf`, so far the HID and report descriptors of HID interfaces.
+pub fn interface(tree: &[Descriptor], number: u8, desc_type: u8, buf: &mut [u8]) -> Option<usize> {
+	let mut current = None;
+	for desc in tree {
+		match *desc {
+			Descriptor::Interface { number: n, .. } => current = Some(n),
+			Descriptor::Hid { report, .. } if current == Some(number) => {
+				return match desc_type {
+					hid::HID => Builder::new(buf).push(desc).finish(),
+					hid::REPORT => report.write(buf),
+					_ => None,
+				};
+			},
+			_ => (),
+		}
+	}
+	None
+}
+
+// Serialises descriptors into a byte buffer, little endian as on the wire. bLength is
+// set once a descriptor is complete, wTotalLength and bNumInterfaces of a configuration
+// descriptor by `finish`. Anything that does not fit makes `finish` return None.
+pub struct Builder<'a> {
+	buf: &'a mut [u8],
+	len: usize,
+	// start of the descriptor being written
+	start: usize,
+	// start of the configuration descriptor
+	config: Option<usize>,
+	interfaces: u8,
+	overflow: bool,
+}
+
+impl<'a> Builder<'a> {
+	pub fn new(buf: &'a mut [u8]) -> Builder<'a> {
+		Builder {
+			buf: buf,
+			len: 0,
+			start: 0,
+			config: None,
+			interfaces: 0,
+			overflow: false,
+		}
+	}
+
+	pub fn device(&mut self, desc: &DeviceDescriptor) -> &mut Builder<'a> {
+		self.begin(DEVICE);
+		self.put16(desc.bcd_usb);
+		self.put8(desc.class);
+		self.put8(desc.subclass);
+		self.put8(desc.proto);
+		self.put8(desc.mps);
+		self.put16(desc.vendor);
+		self.put16(desc.product);
+		self.put16(desc.bcd_device);
+		self.put8(desc.ivendor);
+		self.put8(desc.iproduct);
+		self.put8(desc.iserial);
+		self.put8(desc.numconfig);
+		self.end()
+	}
+
+	// device qualifier, the fields of the device descriptor that differ at the other speed
+	pub fn qualifier(&mut self, desc: &DeviceDescriptor) -> &mut Builder<'a> {
+		self.begin(DEVICE_QUALIFIER);
+		self.put16(desc.bcd_usb);
+		self.put8(desc.class);
+		self.put8(desc.subclass);
+		self.put8(desc.proto);
+		self.put8(desc.mps);
+		self.put8(desc.numconfig);
+		self.put8(0); // bReserved
+		self.end()
+	}
+
+	pub fn push(&mut self, desc: &Descriptor) -> &mut Builder<'a> {
+		match *desc {
+			Descriptor::Configuration { value, iconfiguration, attributes, max_power } => {
+				self.config = Some(self.len);
+				self.interfaces = 0;
+				self.begin(CONFIGURATION);
+				self.put16(0); // wTotalLength
+				self.put8(0); // bNumInterfaces
+				self.put8(value);
+				self.put8(iconfiguration);
+				self.put8(attributes);
+				self.put8(max_power);
+			},
+			Descriptor::Association { first, count, class, subclass, proto, ifunction } => {
+				self.begin(INTERFACE_ASSOCIATION);
+				self.put8(first);
+				self.put8(count);
+				self.put8(class);
+				self.put8(subclass);
+				self.put8(proto);
+				self.put8(ifunction);
+			},
+			Descriptor::Interface { number, alternate, endpoints, class, subclass, proto, iinterface } => {
+				// count alternate setting 0 only
+				if alternate == 0 {
+					self.interfaces += 1;
+				}
+				self.begin(INTERFACE);
+				self.put8(number);
+				self.put8(alternate);
+				self.put8(endpoints);
+				self.put8(class);
+				self.put8(subclass);
+				self.put8(proto);
+				self.put8(iinterface);
+			},
+			Descriptor::Endpoint { address, attributes, mps, interval } => {
+				self.begin(ENDPOINT);
+				self.put8(address);
+				self.put8(attributes);
+				self.put16(mps);
+				self.put8(interval);
+			},
+			Descriptor::Class { desc_type, data } => {
+				self.begin(desc_type);
+				self.put(data);
+			},
+			Descriptor::Hid { country, report, .. } => {
+				self.begin(hid::HID);
+				self.put16(hid::BCD_HID);
+				self.put8(country);
+				self.put8(1); // bNumDescriptors
+				self.put8(hid::REPORT);
+				self.put16(report.len() as u16);
+			},
+		}
+		self.end()
+	}
+
+	// class-specific descriptor that is not part of a const tree
+	pub fn class(&mut self, desc_type: u8, data: &[u8]) -> &mut Builder<'a> {
+		self.begin(desc_type);
+		self.put(data);
+		self.end()
+	}
+
+	// string descriptor 0
+	pub fn langids(&mut self, langids: &[u16]) -> &mut Builder<'a> {
+		self.begin(STRING);
+		for id in langids {
+			self.put16(*id);
+		}
+		self.end()
+	}
+
+	// string descriptor in UTF-16LE, cut off after the 126 code units that fit
+	pub fn string(&mut self, s: &str) -> &mut Builder<'a> {
+		self.begin(STRING);
+		for unit in s.encode_utf16().take(126) {
+			self.put16(unit);
+		}
+		self.end()
+	}
+
+	// Total length of everything written.
+	pub fn finish(&mut self) -> Option<usize> {
+		if self.overflow {
+			return None;
+		}
+		if let Some(config) = self.config {
+			let total = self.len - config;
+			self.buf[config + 2] = total as u8;
+			self.buf[config + 3] = (total >> 8) as u8;
+			self.buf[config + 4] = self.interfaces;
+		}
+		Some(self.len)
+	}
+
+	fn begin(&mut self, desc_type: u8) {
+		self.start = self.len;
+		self.put8(0); // bLength
+		self.put8(desc_type);
+	}
+
+	fn end(&mut self) -> &mut Builder<'a> {
+		let length = self.len - self.start;
+		if length > 255 {
+			self.overflow = true;
+		} else if !self.overflow {
+			self.buf[self.start] = length as u8;
+		}
+		self
+	}
+
+	fn put8(&mut self, value: u8) {
+		if self.len < self.buf.len() {
+			self.buf[self.len] = value;
+		} else {
+			self.overflow = true;
+		}
+		self.len += 1;
+	}
+
+	fn put16(&mut self, value: u16) {
+		self.put8(value as u8);
+		self.put8((value >> 8) as u8);
+	}
+
+	fn put(&mut self, data: &[u8]) {
+		for byte in data {
+			self.put8(*byte);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs::{self, File};
+	use std::io::Write;
+	use std::path::Path;
+	use std::process::Command;
+
+	#[test]
+	fn device_descriptor_layout() {
+		let desc = DeviceDescriptor {
+			bcd_usb: 0x0200, class: 0xef, subclass: 0x02, proto: 0x01, mps: 64,
+			vendor: 0x1234, product: 0x5678, bcd_device: 0x9abc,
+			ivendor: 1, iproduct: 2, iserial: 3, numconfig: 1,
+		};
+		let mut buf = [0u8; 18];
+		assert_eq!(Builder::new(&mut buf).device(&desc).finish(), Some(18));
+		assert_eq!(buf, [18, DEVICE, 0x00, 0x02, 0xef, 0x02, 0x01, 64,
+			0x34, 0x12, 0x78, 0x56, 0xbc, 0x9a, 1, 2, 3, 1]);
+	}
+
+	#[test]
+	fn configuration_descriptor_layout() {
+		let tree = [
+			Descriptor::Configuration { value: 1, iconfiguration: 4, attributes: 0xc0, max_power: 50 },
+			Descriptor::Interface { number: 0, alternate: 0, endpoints: 1, class: 0xff, subclass: 1, proto: 2, iinterface: 5 },
+			Descriptor::Class { desc_type: 0x24, data: &[0x00, 0x10, 0x01] },
+			Descriptor::Endpoint { address: 0x81, attributes: BULK, mps: 512, interval: 0 },
+			// alternate settings do not count as interfaces
+			Descriptor::Interface { number: 0, alternate: 1, endpoints: 0, class: 0xff, subclass: 0, proto: 0, iinterface: 0 },
+		];
+		let mut buf = [0u8; 64];
+		assert_eq!(configuration(&tree, &mut buf), Some(9 + 9 + 5 + 7 + 9));
+		assert_eq!(buf[..39].to_vec(), vec![
+			9, CONFIGURATION, 39, 0, 1, 1, 4, 0xc0, 50,
+			9, INTERFACE, 0, 0, 1, 0xff, 1, 2, 5,
+			5, 0x24, 0x00, 0x10, 0x01,
+			7, ENDPOINT, 0x81, BULK, 0x00, 0x02, 0,
+			9, INTERFACE, 0, 1, 0, 0xff, 0, 0, 0,
+		]);
+	}
+
+	#[test]
+	fn tree_total_length() {
+		let mut buf = [0u8; 512];
+		let len = configuration(TREE, &mut buf).unwrap();
+		assert_eq!(buf[2] as usize | (buf[3] as usize) << 8, len);
+		assert_eq!(buf[4], 4);
+		// the bLength fields chain up to exactly wTotalLength
+		let mut i = 0;
+		while i < len {
+			assert!(buf[i] >= 2);
+			i += buf[i] as usize;
+		}
+		assert_eq!(i, len);
+	}
+
+	#[test]
+	fn device_qualifier() {
+		let mut buf = [0u8; 10];
+		assert_eq!(get(DEVICE_QUALIFIER, 0, &mut buf), Some(10));
+		assert_eq!(buf, [10, DEVICE_QUALIFIER, 0x00, 0x02, CLASS_MISC, SUBCLASS_COMMON, PROTO_IAD, 64, 1, 0]);
+	}
+
+	#[test]
+	fn other_speed_configuration_is_full_speed() {
+		let tree = [
+			Descriptor::Configuration { value: 1, iconfiguration: 0, attributes: 0xc0, max_power: 50 },
+			Descriptor::Interface { number: 0, alternate: 0, endpoints: 3, class: 0xff, subclass: 0, proto: 0, iinterface: 0 },
+			Descriptor::Endpoint { address: 0x81, attributes: BULK, mps: 512, interval: 0 },
+			Descriptor::Endpoint { address: 0x82, attributes: INTERRUPT, mps: 16, interval: 9 },
+			Descriptor::Endpoint { address: 0x83, attributes: INTERRUPT, mps: 1024, interval: 1 },
+		];
+		let mut buf = [0u8; 64];
+		assert_eq!(other_speed_configuration(&tree, &mut buf), Some(9 + 9 + 3 * 7));
+		assert_eq!(buf[..9], [9, OTHER_SPEED_CONFIGURATION, 39, 0, 1, 1, 0, 0xc0, 50]);
+		assert_eq!(buf[18..39], [
+			7, ENDPOINT, 0x81, BULK, 64, 0, 0,
+			// 32 ms
+			7, ENDPOINT, 0x82, INTERRUPT, 16, 0, 32,
+			7, ENDPOINT, 0x83, INTERRUPT, 64, 0, 1,
+		]);
+
+		// same layout as the configuration descriptor
+		let mut high = [0u8; 512];
+		let mut full = [0u8; 512];
+		assert_eq!(get(OTHER_SPEED_CONFIGURATION, 0, &mut full), configuration(TREE, &mut high));
+		assert_eq!(configuration_value(TREE), Some(1));
+	}
+
+	#[test]
+	fn serial_port_is_an_interface_association() {
+		let mut buf = [0u8; 18];
+		get(DEVICE, 0, &mut buf).unwrap();
+		assert_eq!(buf[4..7], [CLASS_MISC, SUBCLASS_COMMON, PROTO_IAD]);
+		let mut buf = [0u8; 512];
+		configuration(TREE, &mut buf).unwrap();
+		// right after the configuration descriptor, before the communication interface
+		assert_eq!(buf[9..17], [8, INTERFACE_ASSOCIATION, cdc::COMM_INTERFACE, 2,
+			cdc::CLASS_COMM, cdc::SUBCLASS_ACM, cdc::PROTO_AT, STR_INTERFACE]);
+		assert_eq!(buf[17..20], [9, INTERFACE, cdc::COMM_INTERFACE]);
+	}
+
+	#[test]
+	fn too_small_a_buffer_is_no_descriptor() {
+		let mut buf = [0u8; 17];
+		assert_eq!(Builder::new(&mut buf).device(&DEVICE_DESCRIPTOR).finish(), None);
+		let mut buf = [0u8; 32];
+		assert_eq!(configuration(TREE, &mut buf), None);
+	}
+
+	#[test]
+	fn strings_are_utf16le() {
+		let mut buf = [0u8; 16];
+		assert_eq!(Builder::new(&mut buf).string("Ab").finish(), Some(6));
+		assert_eq!(buf[..6], [6, STRING, b'A', 0, b'b', 0]);
+		assert_eq!(Builder::new(&mut buf).string("ä€").finish(), Some(6));
+		assert_eq!(buf[..6], [6, STRING, 0xe4, 0x00, 0xac, 0x20]);
+		assert_eq!(Builder::new(&mut buf).string("").finish(), Some(2));
+		assert_eq!(buf[..2], [2, STRING]);
+	}
+
+	#[test]
+	fn long_strings_are_cut_off() {
+		let long = [b'x'; 200];
+		let mut buf = [0u8; 512];
+		let s = ::core::str::from_utf8(&long).unwrap();
+		assert_eq!(Builder::new(&mut buf).string(s).finish(), Some(254));
+		assert_eq!(buf[0], 254);
+	}
+
+	#[test]
+	fn langids() {
+		let mut buf = [0u8; 8];
+		assert_eq!(get(STRING, 0, &mut buf), Some(4));
+		assert_eq!(buf[..4], [4, STRING, 0x09, 0x04]);
+		assert_eq!(Builder::new(&mut buf).langids(&[0x0409, 0x0407]).finish(), Some(6));
+		assert_eq!(buf[..6], [6, STRING, 0x09, 0x04, 0x07, 0x04]);
+	}
+
+	#[test]
+	fn serial_number_is_hex_most_significant_first() {
+		let serial = serial_number([0x0030_0042, 0x3436_5113, 0xdead_beef]);
+		assert_eq!(&serial[..], &b"DEADBEEF3436511300300042"[..]);
+	}
+
+	// Type checks a crate with the descriptor modules and the configuration tree `tree`,
+	// the error output of rustc if it did not accept it.
+	fn compiles(name: &str, tree: &str) -> Result<(), String> {
+		let usb = Path::new(env!("CARGO_MANIFEST_DIR")).join(file!());
+		let usb = usb.parent().unwrap().display();
+		let dir = env::temp_dir().join(format!("descriptors-{}", name));
+		fs::create_dir_all(&dir).unwrap();
+		let source = dir.join("lib.rs");
+		let mut file = File::create(&source).unwrap();
+		write!(file, "
+			#![allow(warnings)]
+			extern crate core;
+			#[macro_use]
+			mod usb {{
+				#[path = \"{usb}/control.rs\"] pub mod control;
+				#[macro_use] #[path = \"{usb}/descriptor.rs\"] pub mod descriptor;
+				#[path = \"{usb}/cdc.rs\"] pub mod cdc;
+				#[path = \"{usb}/hid.rs\"] pub mod hid;
+				#[path = \"{usb}/keyboard.rs\"] pub mod keyboard;
+				#[path = \"{usb}/digitizer.rs\"] pub mod digitizer;
+			}}
+			mod check {{
+				use usb::descriptor::*;
+				descriptors! {{
+					device DEVICE {{
+						bcd_usb: 0x0200, class: 0xff, subclass: 0, proto: 0, mps: 64,
+						vendor: 0, product: 0, bcd_device: 0, ivendor: 0, iproduct: 0, iserial: 0,
+						numconfig: 1,
+					}}
+					strings 1;
+					tree TREE {{ {tree} }}
+				}}
+			}}
+		", usb = usb, tree = tree).unwrap();
+		let rustc = env::var("RUSTC").unwrap_or("rustc".into());
+		let output = Command::new(rustc)
+			.args(&["--crate-type", "lib", "--emit", "metadata", "--cfg", "feature=\"usbip\"", "--out-dir"])
+			.arg(&dir)
+			.arg(&source)
+			.output()
+			.expect("rustc did not run");
+		let _ = fs::remove_dir_all(&dir);
+		if output.status.success() {
+			Ok(())
+		} else {
+			Err(String::from_utf8_lossy(&output.stderr).into_owned())
+		}
+	}
+
+	// `tree` compiles.
+	fn accepted(name: &str, tree: &str) {
+		if let Err(errors) = compiles(name, tree) {
+			panic!("{} rejected:\n{}", name, errors);
+		}
+	}
+
+	// `tree` fails a check of `descriptors!` and nothing else.
+	fn rejected(name: &str, tree: &str) {
+		match compiles(name, tree) {
+			Ok(()) => panic!("{} accepted", name),
+			Err(errors) => {
+				let checks = errors.matches("error[E0308]").count();
+				let all = errors.matches("error[").count();
+				assert!(checks > 0 && checks == all && errors.contains("descriptor_check"),
+					"{} not rejected by a check:\n{}", name, errors);
+			},
+		}
+	}
+
+	// one vendor specific interface with endpoint `endpoint`
+	fn endpoint(endpoint: &str) -> String {
+		format!("
+			configuration {{ value: 1, interfaces: 1, iconfiguration: 0, attributes: 0xc0, max_power: 50 }}
+			interface {{ number: 0, endpoints: 1, class: 0xff, subclass: 0, proto: 0, iinterface: 0 }} [
+				{}
+			]
+		", endpoint)
+	}
+
+	// `count` HID interfaces
+	fn hid_interfaces(count: usize) -> String {
+		let mut tree = format!("
+			configuration {{ value: 1, interfaces: {}, iconfiguration: 0, attributes: 0xc0, max_power: 50 }}
+		", count);
+		for i in 0..count {
+			tree += &format!("
+				interface {{ number: {0}, endpoints: 1, class: ::usb::hid::CLASS_HID, subclass: 0, proto: 0, iinterface: 0 }} [
+					hid {{ country: 0, report: ::usb::keyboard::report }}
+					endpoint {{ address: 0x81 + {0}, attributes: INTERRUPT, mps: 8, interval: 4 }}
+				]
+			", i);
+		}
+		tree
+	}
+
+	#[test]
+	fn descriptors_rejects_invalid_endpoints() {
+		accepted("valid", &endpoint("endpoint { address: 0x81, attributes: BULK, mps: 512, interval: 0 }"));
+		rejected("number", &endpoint("endpoint { address: 0x88, attributes: INTERRUPT, mps: 8, interval: 1 }"));
+		rejected("zero", &endpoint("endpoint { address: 0x00, attributes: INTERRUPT, mps: 8, interval: 1 }"));
+		rejected("bulk-mps", &endpoint("endpoint { address: 0x81, attributes: BULK, mps: 64, interval: 0 }"));
+		rejected("mps", &endpoint("endpoint { address: 0x81, attributes: INTERRUPT, mps: 1025, interval: 1 }"));
+	}
+
+	// interfaces 0 and 1 with association `association` in front
+	fn associated(association: &str) -> String {
+		format!("
+			configuration {{ value: 1, interfaces: 2, iconfiguration: 0, attributes: 0xc0, max_power: 50 }}
+			{}
+			interface {{ number: 0, endpoints: 0, class: 0xff, subclass: 0, proto: 0, iinterface: 0 }} []
+			interface {{ number: 1, endpoints: 0, class: 0xff, subclass: 0, proto: 0, iinterface: 0 }} []
+		", association)
+	}
+
+	#[test]
+	fn descriptors_checks_associations() {
+		accepted("association", &associated(
+			"association { first: 0, count: 2, class: 0xff, subclass: 0, proto: 0, ifunction: 0 }"));
+		rejected("association-first", &associated(
+			"association { first: 1, count: 2, class: 0xff, subclass: 0, proto: 0, ifunction: 0 }"));
+		rejected("association-string", &associated(
+			"association { first: 0, count: 2, class: 0xff, subclass: 0, proto: 0, ifunction: 1 }"));
+	}
+
+	#[test]
+	fn descriptors_rejects_too_many_hid_interfaces() {
+		accepted("hid", &hid_interfaces(hid::MAX_FUNCTIONS));
+		rejected("too-many-hid", &hid_interfaces(hid::MAX_FUNCTIONS + 1));
+	}
+}
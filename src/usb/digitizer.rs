@@ -0,0 +1,342 @@
+// Touch screen digitizer (HID usage tables, digitizer page) for the touch panel of the
+// LCD. One report carries up to `MAX_CONTACTS` fingers, so a single touch is a report
+// with one contact. Hosts that support multi-touch read the contact count maximum and
+// switch the Device Mode feature to multi-input, until then the first contact is sent as
+// an absolute mouse of a collection of its own.
+
+use super::hid::{self, Report};
+#[cfg(not(feature = "usbip"))]
+use super::Usb;
+
+// interface number and endpoint address in `descriptor::TREE`
+pub const INTERFACE : u8 = 3;
+pub const IN_EP : u8 = 0x84;
+// polling interval, 2^(4-1) microframes = 1 ms at high speed
+pub const INTERVAL : u8 = 4;
+
+// the FT5336 of the board tracks up to 5 touches
+pub const MAX_CONTACTS : usize = 5;
+
+// the FT5336 tells the fingers apart by a 4 bit touch ID
+pub const MAX_CONTACT_ID : u8 = 0x0f;
+
+pub const TOUCH_REPORT_ID : u8 = 1;
+pub const MAX_COUNT_REPORT_ID : u8 = 2;
+pub const CONFIGURATION_REPORT_ID : u8 = 3;
+pub const MOUSE_REPORT_ID : u8 = 4;
+// report ID, 6 bytes per contact and the contact count
+pub const REPORT_LEN : usize = 1 + 6 * MAX_CONTACTS + 1;
+// report ID, buttons, x and y
+pub const MOUSE_REPORT_LEN : usize = 6;
+
+// Device Mode values of the configuration feature report
+pub const MODE_MOUSE : u8 = 0;
+pub const MODE_SINGLE_INPUT : u8 = 1;
+pub const MODE_MULTI_INPUT : u8 = 2;
+
+// initial feature reports: the contact count maximum, Device Mode and Device Identifier
+pub const FEATURES : &'static [&'static [u8]] = &[
+	&[MAX_COUNT_REPORT_ID, MAX_CONTACTS as u8],
+	&[CONFIGURATION_REPORT_ID, MODE_MOUSE, 0],
+];
+
+// LCD size in pixels, the coordinates the touch controller reports
+pub const LCD_WIDTH : u16 = 480;
+pub const LCD_HEIGHT : u16 = 272;
+// the coordinates the host gets go from 0 to LOGICAL_MAX
+pub const LOGICAL_MAX : u16 = 32767;
+// active area of the LCD in 0.01 mm
+const PHYSICAL_WIDTH : i32 = 9504;
+const PHYSICAL_HEIGHT : i32 = 5386;
+
+// usages of the digitizer page
+const TOUCH_SCREEN : u16 = 0x04;
+const FINGER : u16 = 0x22;
+const TIP_SWITCH : u16 = 0x42;
+const CONTACT_ID : u16 = 0x51;
+const CONTACT_COUNT : u16 = 0x54;
+const CONTACT_COUNT_MAXIMUM : u16 = 0x55;
+const DEVICE_CONFIGURATION : u16 = 0x0e;
+const DEVICE_SETTINGS : u16 = 0x23;
+const DEVICE_MODE : u16 = 0x52;
+const DEVICE_IDENTIFIER : u16 = 0x53;
+// usages of the generic desktop page
+const POINTER : u16 = 0x01;
+const MOUSE : u16 = 0x02;
+const X : u16 = 0x30;
+const Y : u16 = 0x31;
+
+// SI linear length in cm, with exponent -3 the physical coordinates are in 0.01 mm
+const UNIT_CM : u32 = 0x11;
+
+pub fn report(r: &mut Report) {
+	r.usage_page(hid::DIGITIZER).usage(TOUCH_SCREEN)
+		.collection(hid::APPLICATION)
+			.report_id(TOUCH_REPORT_ID)
+			.logical_minimum(0).physical_minimum(0);
+	for _ in 0..MAX_CONTACTS {
+		r.usage(FINGER)
+			.collection(hid::LOGICAL)
+				// tip switch and padding
+				.usage(TIP_SWITCH).logical_maximum(1)
+				.report_size(1).report_count(1).input(hid::VARIABLE)
+				.report_count(7).input(hid::CONSTANT)
+				.usage(CONTACT_ID).logical_maximum(MAX_CONTACT_ID as i32)
+				.report_size(8).report_count(1).input(hid::VARIABLE)
+				// position
+				.usage_page(hid::GENERIC_DESKTOP).logical_maximum(LOGICAL_MAX as i32).report_size(16)
+				.unit(UNIT_CM).unit_exponent(-3)
+				.usage(X).physical_maximum(PHYSICAL_WIDTH).input(hid::VARIABLE)
+				.usage(Y).physical_maximum(PHYSICAL_HEIGHT).input(hid::VARIABLE)
+				.unit(0).unit_exponent(0).physical_maximum(0)
+				.usage_page(hid::DIGITIZER)
+			.end_collection();
+	}
+	r.usage(CONTACT_COUNT).logical_maximum(MAX_CONTACTS as i32)
+			.report_size(8).report_count(1).input(hid::VARIABLE)
+			.report_id(MAX_COUNT_REPORT_ID)
+			.usage(CONTACT_COUNT_MAXIMUM).feature(hid::VARIABLE)
+		.end_collection();
+	r.usage_page(hid::DIGITIZER).usage(DEVICE_CONFIGURATION)
+		.collection(hid::APPLICATION)
+			.report_id(CONFIGURATION_REPORT_ID)
+			.usage(DEVICE_SETTINGS)
+			.collection(hid::LOGICAL)
+				.usage(DEVICE_MODE).usage(DEVICE_IDENTIFIER)
+				.logical_maximum(MODE_MULTI_INPUT as i32)
+				.report_size(8).report_count(2).feature(hid::VARIABLE)
+			.end_collection()
+		.end_collection();
+	r.usage_page(hid::GENERIC_DESKTOP).usage(MOUSE)
+		.collection(hid::APPLICATION)
+			.report_id(MOUSE_REPORT_ID)
+			.usage(POINTER)
+			.collection(hid::PHYSICAL)
+				// left button and padding
+				.usage_page(hid::BUTTON).usage_minimum(1).usage_maximum(1)
+				.logical_maximum(1)
+				.report_size(1).report_count(1).input(hid::VARIABLE)
+				.report_count(7).input(hid::CONSTANT)
+				.usage_page(hid::GENERIC_DESKTOP).usage(X).usage(Y)
+				.logical_maximum(LOGICAL_MAX as i32)
+				.report_size(16).report_count(2).input(hid::VARIABLE)
+			.end_collection()
+		.end_collection();
+}
+
+// Scales `pixel` of an axis `pixels` long to 0 to LOGICAL_MAX.
+pub fn scale(pixel: u16, pixels: u16) -> u16 {
+	let pixel = ::core::cmp::min(pixel, pixels - 1) as u32;
+	(pixel * LOGICAL_MAX as u32 / (pixels - 1) as u32) as u16
+}
+
+// A finger on the panel, in logical coordinates
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Contact {
+	pub id: u8,
+	// touching, false when the finger was lifted
+	pub tip: bool,
+	pub x: u16,
+	pub y: u16,
+}
+
+impl Contact {
+	// Contact `id` touching the LCD at pixel `x`, `y`.
+	pub fn from_lcd(id: u8, x: u16, y: u16) -> Contact {
+		Contact { id: id, tip: true, x: scale(x, LCD_WIDTH), y: scale(y, LCD_HEIGHT) }
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TouchReport {
+	pub contacts: [Contact; MAX_CONTACTS],
+	// the first `count` contacts are valid
+	pub count: usize,
+}
+
+impl TouchReport {
+	pub fn empty() -> TouchReport {
+		let none = Contact { id: 0, tip: false, x: 0, y: 0 };
+		TouchReport { contacts: [none; MAX_CONTACTS], count: 0 }
+	}
+
+	pub fn single(contact: Contact) -> TouchReport {
+		let mut report = TouchReport::empty();
+		report.push(contact);
+		report
+	}
+
+	// Adds `contact`, false if the report is full.
+	pub fn push(&mut self, contact: Contact) -> bool {
+		if self.count == MAX_CONTACTS {
+			return false;
+		}
+		self.contacts[self.count] = contact;
+		self.count += 1;
+		true
+	}
+
+	// Whether a finger is on the panel.
+	pub fn touching(&self) -> bool {
+		self.contacts[..self.count].iter().any(|c| c.tip)
+	}
+
+	pub fn bytes(&self) -> [u8; REPORT_LEN] {
+		let mut data = [0; REPORT_LEN];
+		data[0] = TOUCH_REPORT_ID;
+		for (i, contact) in self.contacts[..self.count].iter().enumerate() {
+			let field = &mut data[1 + 6 * i..1 + 6 * (i + 1)];
+			field[0] = contact.tip as u8;
+			field[1] = contact.id;
+			field[2] = contact.x as u8;
+			field[3] = (contact.x >> 8) as u8;
+			field[4] = contact.y as u8;
+			field[5] = (contact.y >> 8) as u8;
+		}
+		data[REPORT_LEN - 1] = self.count as u8;
+		data
+	}
+
+	// Mouse report of the first finger on the panel, the button is down while it touches.
+	// Once all are lifted, the button goes up where the first lifted one was.
+	pub fn mouse_bytes(&self) -> [u8; MOUSE_REPORT_LEN] {
+		let contacts = &self.contacts[..self.count];
+		let contact = contacts.iter().find(|c| c.tip).or(contacts.first());
+		let mut data = [0; MOUSE_REPORT_LEN];
+		data[0] = MOUSE_REPORT_ID;
+		if let Some(contact) = contact {
+			data[1] = contact.tip as u8;
+			data[2] = contact.x as u8;
+			data[3] = (contact.x >> 8) as u8;
+			data[4] = contact.y as u8;
+			data[5] = (contact.y >> 8) as u8;
+		}
+		data
+	}
+}
+
+// Turns the touch points the panel reports into touch reports. A contact keeps the touch
+// ID of the controller, a contact that disappears is reported lifted once, ahead of the
+// ones still touching.
+pub struct Tracker {
+	last: TouchReport,
+}
+
+impl Tracker {
+	pub fn new() -> Tracker {
+		Tracker { last: TouchReport::empty() }
+	}
+
+	// Report for the touch points `points`, (touch ID, x, y) in LCD pixels. None if the
+	// host would learn nothing new from it.
+	pub fn report<I>(&self, points: I) -> Option<TouchReport> where I: IntoIterator<Item=(u8, u16, u16)> {
+		let mut touching = TouchReport::empty();
+		for (id, x, y) in points.into_iter().take(MAX_CONTACTS) {
+			touching.push(Contact::from_lcd(id & MAX_CONTACT_ID, x, y));
+		}
+		let touching = &touching.contacts[..touching.count];
+		let mut report = TouchReport::empty();
+		for contact in self.last.contacts[..self.last.count].iter() {
+			if contact.tip && !touching.iter().any(|c| c.id == contact.id) {
+				report.push(Contact { tip: false, ..*contact });
+			}
+		}
+		// the ones that do not fit come with the next report
+		for contact in touching {
+			report.push(*contact);
+		}
+		if report == self.last || (report.count == 0 && !self.last.touching()) {
+			None
+		} else {
+			Some(report)
+		}
+	}
+
+	// `report` went out, the next one is relative to it.
+	pub fn sent(&mut self, report: &TouchReport) {
+		self.last = *report;
+	}
+}
+
+// Handle to the digitizer, get it from `Usb::digitizer`.
+#[cfg(not(feature = "usbip"))]
+pub struct Digitizer<'a> {
+	usb: &'a mut Usb,
+}
+
+#[cfg(not(feature = "usbip"))]
+impl<'a> Digitizer<'a> {
+	pub fn new(usb: &'a mut Usb) -> Digitizer<'a> {
+		Digitizer { usb: usb }
+	}
+
+	// Device Mode the host set, `MODE_MOUSE` until it asks for touch reports.
+	pub fn mode(&mut self) -> u8 {
+		self.usb.with(|driver| {
+			driver.hid(INTERFACE)
+				.and_then(|hid| hid.feature(CONFIGURATION_REPORT_ID).and_then(|f| f.get(1).cloned()))
+				.unwrap_or(MODE_MOUSE)
+		})
+	}
+
+	// Starts sending `report`, as a mouse report in mouse mode. False while the last one
+	// is still waiting for the host.
+	pub fn send(&mut self, report: &TouchReport) -> bool {
+		let mouse = self.mode() == MODE_MOUSE;
+		self.usb.with(|driver| if mouse {
+			driver.send_report(INTERFACE, &report.mouse_bytes())
+		} else {
+			driver.send_report(INTERFACE, &report.bytes())
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn contacts(report: &TouchReport) -> &[Contact] {
+		&report.contacts[..report.count]
+	}
+
+	#[test]
+	fn contacts_keep_the_touch_id() {
+		let mut tracker = Tracker::new();
+		let report = tracker.report([(3, 10, 20), (7, 30, 40)].iter().cloned()).unwrap();
+		assert_eq!(contacts(&report), [Contact::from_lcd(3, 10, 20), Contact::from_lcd(7, 30, 40)]);
+		tracker.sent(&report);
+
+		// the first finger lifts, the second one is still contact 7
+		let report = tracker.report([(7, 31, 40)].iter().cloned()).unwrap();
+		assert_eq!(contacts(&report), [Contact { tip: false, ..Contact::from_lcd(3, 10, 20) },
+			Contact::from_lcd(7, 31, 40)]);
+		tracker.sent(&report);
+
+		let report = tracker.report([(7, 31, 40)].iter().cloned()).unwrap();
+		assert_eq!(contacts(&report), [Contact::from_lcd(7, 31, 40)]);
+		tracker.sent(&report);
+		assert_eq!(tracker.report([(7, 31, 40)].iter().cloned()), None);
+	}
+
+	#[test]
+	fn lifted_contacts_are_reported_once() {
+		let mut tracker = Tracker::new();
+		assert_eq!(tracker.report(None), None);
+		let report = tracker.report([(0, 10, 20)].iter().cloned()).unwrap();
+		tracker.sent(&report);
+
+		let report = tracker.report(None).unwrap();
+		assert_eq!(contacts(&report), [Contact { tip: false, ..Contact::from_lcd(0, 10, 20) }]);
+		tracker.sent(&report);
+		assert_eq!(tracker.report(None), None);
+	}
+
+	#[test]
+	fn mouse_follows_the_first_finger() {
+		let (x, y) = (scale(100, LCD_WIDTH), scale(50, LCD_HEIGHT));
+		let report = TouchReport::single(Contact::from_lcd(2, 100, 50));
+		assert_eq!(report.mouse_bytes(), [MOUSE_REPORT_ID, 1, x as u8, (x >> 8) as u8, y as u8, (y >> 8) as u8]);
+		let report = TouchReport::single(Contact { tip: false, ..Contact::from_lcd(2, 100, 50) });
+		assert_eq!(report.mouse_bytes(), [MOUSE_REPORT_ID, 0, x as u8, (x >> 8) as u8, y as u8, (y >> 8) as u8]);
+	}
+}
@@ -0,0 +1,131 @@
+use super::hw::Hardware;
+use super::error::UsbError;
+use super::request::Device;
+use super::endpoint::{self, Endpoint};
+use super::fifo::{self, Layout};
+use super::interrupt::{self, State};
+use super::cdc::Acm;
+use super::hid::Hid;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+	// the driver copies every packet through the FIFO windows
+	Slave,
+	// the internal DMA of the core moves packets between the FIFOs and RAM. The driver
+	// must not move once started since the core keeps pointers into it, and the buffers
+	// must not be cached.
+	Dma,
+}
+
+// The OTG HS driver: the core it runs on and all of its state.
+pub struct Driver<H> {
+	hw: H,
+	state: State,
+	mode: Mode,
+}
+
+impl<H: Hardware> Driver<H> {
+	pub fn new(hw: H) -> Driver<H> {
+		Driver {
+			hw: hw,
+			state: State::new(),
+			mode: Mode::Slave,
+		}
+	}
+
+	// Takes effect on the next `start`.
+	pub fn set_mode(&mut self, mode: Mode) {
+		self.mode = mode;
+	}
+
+	pub fn hw(&mut self) -> &mut H {
+		&mut self.hw
+	}
+
+	// Resets the driver state and unmasks the core interrupts.
+	pub fn start(&mut self) {
+		interrupt::start(&mut self.hw, &mut self.state, self.mode);
+	}
+
+	// Handles all pending core interrupts.
+	pub fn poll(&mut self) {
+		interrupt::dispatch(&mut self.hw, &mut self.state);
+	}
+
+	// Halts endpoint `address` (bEndpointAddress), the host gets STALL until it sends
+	// CLEAR_FEATURE(ENDPOINT_HALT) or `unstall` is called. False if the core does not
+	// have the endpoint.
+	pub fn stall(&mut self, address: u8) -> bool {
+		interrupt::halt(&mut self.hw, &mut self.state, address, true)
+	}
+
+	pub fn unstall(&mut self, address: u8) -> bool {
+		interrupt::halt(&mut self.hw, &mut self.state, address, false)
+	}
+
+	// Activates a data endpoint outside of the configuration descriptor, SET_CONFIGURATION
+	// and bus resets deactivate it again. False if the core does not have the endpoint.
+	pub fn activate(&mut self, ep: &Endpoint) -> bool {
+		if !endpoint::activate(&mut self.hw, ep) {
+			return false;
+		}
+		interrupt::rearm(&mut self.hw, &mut self.state);
+		true
+	}
+
+	pub fn deactivate(&mut self, address: u8) -> bool {
+		interrupt::deactivate(&mut self.hw, &mut self.state, address)
+	}
+
+	// Enables the OUT endpoints that wait for a buffer of the receive pool.
+	pub fn rearm(&mut self) {
+		interrupt::rearm(&mut self.hw, &mut self.state);
+	}
+
+	// Starts an IN packet with the start of `data` on endpoint `address`, returns how many
+	// bytes it took, 0 while the endpoint is busy.
+	pub fn send(&mut self, address: u8, data: &[u8]) -> usize {
+		interrupt::send(&mut self.hw, &mut self.state, address, data)
+	}
+
+	// Sends a zero length packet on endpoint `address`, false while the endpoint is busy.
+	pub fn send_zlp(&mut self, address: u8) -> bool {
+		interrupt::send_zlp(&mut self.hw, &mut self.state, address)
+	}
+
+	// Sends an input report on HID interface `interface`, false while the last one is
+	// still being sent or if it is longer than the max packet size.
+	pub fn send_report(&mut self, interface: u8, report: &[u8]) -> bool {
+		interrupt::send_report(&mut self.hw, &mut self.state, interface, report)
+	}
+
+	// Replaces the FIFO layout the bus reset programmed, for endpoints activated through
+	// `activate`. Get one from `fifo::plan`.
+	pub fn program_fifos(&mut self, layout: &Layout) {
+		fifo::program(&mut self.hw, layout);
+	}
+
+	// chapter 9 state of the device: address, configuration, halted endpoints
+	pub fn device(&self) -> &Device {
+		self.state.device()
+	}
+
+	// line coding and control lines of the CDC-ACM serial port
+	pub fn acm(&self) -> &Acm {
+		self.state.acm()
+	}
+
+	// idle rate, protocol and output reports of HID interface `interface`
+	pub fn hid(&mut self, interface: u8) -> Option<&mut Hid> {
+		self.state.hid(interface)
+	}
+
+	// Most recent error the driver recovered from, cleared by reading it.
+	pub fn take_error(&mut self) -> Option<UsbError> {
+		self.state.take_error()
+	}
+
+	pub fn error_count(&self) -> u32 {
+		self.state.error_count()
+	}
+}
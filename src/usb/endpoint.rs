@@ -0,0 +1,169 @@
+// Data endpoints 1 to 7. Endpoint 0 is set up by the reset and enumeration handlers.
+
+use super::hw::Hardware;
+use super::descriptor::{self, Descriptor};
+use super::error::UsbError;
+
+// endpoints per direction, including endpoint 0
+pub const NUM_ENDPOINTS : u8 = 8;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EpType {
+	Isochronous,
+	Bulk,
+	Interrupt,
+}
+
+impl EpType {
+	// transfer type of an endpoint descriptor's bmAttributes, None for control
+	pub fn from_attributes(attributes: u8) -> Option<EpType> {
+		match attributes & 0x3 {
+			descriptor::ISOCHRONOUS => Some(EpType::Isochronous),
+			descriptor::BULK => Some(EpType::Bulk),
+			descriptor::INTERRUPT => Some(EpType::Interrupt),
+			_ => None,
+		}
+	}
+
+	// DIEPCTLx/DOEPCTLx.EPTYP
+	fn eptyp(self) -> u8 {
+		match self {
+			EpType::Isochronous => 0x1,
+			EpType::Bulk => 0x2,
+			EpType::Interrupt => 0x3,
+		}
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Endpoint {
+	// bEndpointAddress, bit 7 set for IN
+	pub address: u8,
+	pub ep_type: EpType,
+	pub mps: u16,
+	// TX FIFO of an IN endpoint
+	pub fifo: u8,
+}
+
+impl Endpoint {
+	// Endpoint of an endpoint descriptor, IN endpoint n sends from TX FIFO n.
+	pub fn from_descriptor(desc: &Descriptor) -> Option<Endpoint> {
+		match *desc {
+			Descriptor::Endpoint { address, attributes, mps, .. } => {
+				EpType::from_attributes(attributes).map(|ep_type| Endpoint {
+					address: address,
+					ep_type: ep_type,
+					mps: mps & 0x7ff,
+					fifo: address & 0xf,
+				})
+			},
+			_ => None,
+		}
+	}
+
+	pub fn number(&self) -> u8 {
+		self.address & 0xf
+	}
+
+	pub fn is_in(&self) -> bool {
+		self.address & 0x80 != 0
+	}
+}
+
+// Whether `address` (bEndpointAddress) names a data endpoint the core has.
+pub fn is_data(address: u8) -> bool {
+	let n = address & 0x7f;
+	n != 0 && n < NUM_ENDPOINTS
+}
+
+// Activates `ep` with DATA0 as the next data PID and unmasks its interrupts. Until a
+// transfer is started the core NAKs it. False if `ep` is not a data endpoint of the core.
+pub fn activate(hw: &mut Hardware, ep: &Endpoint) -> bool {
+	let (n, mps, eptyp, fifo) = (ep.number(), ep.mps, ep.ep_type.eptyp(), ep.fifo);
+	if !is_data(ep.address) {
+		return false;
+	}
+	if ep.is_in() {
+		diepctl!(hw.device(), n, |r| r.update(|r| {
+			r.set_mpsiz(mps);
+			r.set_eptyp(eptyp);
+			r.set_txfnum(fifo);
+			r.set_sd0pid_sevnfrm(true);
+			r.set_snak(true);
+			r.set_usbaep(true);
+		}));
+		hw.device().otg_hs_daintmsk.update(|r| { let iepm = r.iepm(); r.set_iepm(iepm | (1 << n)); });
+	} else {
+		doepctl!(hw.device(), n, |r| r.update(|r| {
+			r.set_mpsiz(mps);
+			r.set_eptyp(eptyp);
+			r.set_sd0pid_sevnfrm(true);
+			r.set_snak(true);
+			r.set_usbaep(true);
+		}));
+		hw.device().otg_hs_daintmsk.update(|r| { let oepm = r.oepm(); r.set_oepm(oepm | (1 << n)); });
+	}
+	true
+}
+
+// Aborts a transfer in progress on endpoint `address` and deactivates it. An enabled OUT
+// endpoint can only be disabled while the global OUT NAK is in effect, see
+// `interrupt::deactivate`. False if `address` is not a data endpoint of the core, an
+// error if the core does not confirm the disable.
+pub fn deactivate(hw: &mut Hardware, address: u8) -> Result<bool, UsbError> {
+	let n = address & 0x7f;
+	let ctl = match diepctl!(hw.device(), n, |r| r.read()) {
+		Some(ctl) => ctl,
+		None => return Ok(false),
+	};
+	let mut disabled = true;
+	if address & 0x80 != 0 {
+		hw.device().otg_hs_daintmsk.update(|r| { let iepm = r.iepm(); r.set_iepm(iepm & !(1 << n)); });
+		if ctl.epena() {
+			disabled = hw.disable_in(n);
+		}
+		diepctl!(hw.device(), n, |r| r.update(|r| {
+			r.set_snak(true);
+			r.set_usbaep(false);
+		}));
+		if ctl.usbaep() {
+			hw.flush_tx(ctl.txfnum());
+		}
+	} else {
+		hw.device().otg_hs_daintmsk.update(|r| { let oepm = r.oepm(); r.set_oepm(oepm & !(1 << n)); });
+		if out_enabled(hw, n) {
+			disabled = hw.disable_out(n);
+		}
+		doepctl!(hw.device(), n, |r| r.update(|r| {
+			r.set_snak(true);
+			r.set_usbaep(false);
+		}));
+	}
+	if disabled { Ok(true) } else { Err(UsbError::DisableTimeout { ep: address }) }
+}
+
+// Whether OUT endpoint `n` 1 to 7 is enabled, disabling it needs the global OUT NAK.
+pub fn out_enabled(hw: &mut Hardware, n: u8) -> bool {
+	doepctl!(hw.device(), n, |r| r.read().epena()).unwrap_or(false)
+}
+
+// Deactivates endpoints 1 to 7 in both directions, the first endpoint that did not
+// confirm the disable is the error.
+pub fn deactivate_all(hw: &mut Hardware) -> Result<(), UsbError> {
+	let mut result = Ok(());
+	for n in 1..NUM_ENDPOINTS {
+		for &address in [n, 0x80 | n].iter() {
+			if let Err(e) = deactivate(hw, address) {
+				result = result.and(Err(e));
+			}
+		}
+	}
+	result
+}
+
+// Activates the endpoints of the configuration tree `tree`.
+pub fn configure(hw: &mut Hardware, tree: &[Descriptor]) {
+	for ep in tree.iter().filter_map(Endpoint::from_descriptor) {
+		activate(hw, &ep);
+	}
+}
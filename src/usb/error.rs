@@ -0,0 +1,45 @@
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UsbError {
+	// one of the ULPI pins is already used by something else
+	PinInUse,
+	// the application accessed a host mode register in device mode or vice versa
+	ModeMismatch,
+	// enumeration finished at a speed the driver is not set up for (DSTS.ENUMSPD)
+	UnsupportedSpeed(u8),
+	// a receive status entry the driver cannot handle
+	UnexpectedPacket {
+		ep: u8,
+		status: u8,
+		dpid: u8,
+		count: u16,
+	},
+	// an OUT packet of a data endpoint that had no receive buffer, its data is dropped
+	PacketDropped {
+		ep: u8,
+		count: u16,
+	},
+	// the FIFOs of the enabled endpoints need more than the FIFO RAM has, in words
+	FifoRamExhausted {
+		words: u16,
+	},
+	// endpoint `ep` (bEndpointAddress) did not report EPDISD in time, 0 if the global OUT
+	// NAK did not take effect. The endpoint is deactivated anyway.
+	DisableTimeout {
+		ep: u8,
+	},
+}
+
+impl UsbError {
+	// Number of the kind of error, for a display that only shows digits.
+	pub fn code(&self) -> u8 {
+		match *self {
+			UsbError::PinInUse => 1,
+			UsbError::ModeMismatch => 2,
+			UsbError::UnsupportedSpeed(_) => 3,
+			UsbError::UnexpectedPacket { .. } => 4,
+			UsbError::PacketDropped { .. } => 5,
+			UsbError::FifoRamExhausted { .. } => 6,
+			UsbError::DisableTimeout { .. } => 7,
+		}
+	}
+}
@@ -0,0 +1,89 @@
+// Placement of the RX FIFO and the TX FIFOs in the FIFO RAM of the core. All sizes are
+// in 32 bit words.
+
+use super::hw::Hardware;
+use super::error::UsbError;
+use super::endpoint::{Endpoint, NUM_ENDPOINTS};
+
+// 4 KB FIFO RAM
+pub const RAM_WORDS : u16 = 1024;
+// in DMA mode the core keeps the DMA addresses of the endpoints at the end of the FIFO
+// RAM, one word per endpoint and direction
+const DMA_WORDS : u16 = 2 * NUM_ENDPOINTS as u16;
+// minimum depth of a TX FIFO
+const MIN_TX_DEPTH : u16 = 16;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Layout {
+	// the RX FIFO starts at 0
+	pub rx_depth: u16,
+	// start and depth of TX FIFO 0 to 7, depth 0 if no endpoint uses it
+	pub tx: [(u16, u16); 8],
+}
+
+fn words(bytes: u16) -> u16 {
+	(bytes + 3) / 4
+}
+
+// Lays out the FIFOs for control endpoint 0 with max packet size `mps0` and the data
+// endpoints `endpoints`. The RX FIFO is sized as in RM0385 "FIFO RAM allocation" with
+// room for two packets, IN endpoints get two packets each so that the next packet
+// can be written while the previous one is on the bus.
+pub fn plan<I>(mps0: u16, endpoints: I) -> Result<Layout, UsbError> where I: IntoIterator<Item=Endpoint> {
+	let mut tx_depth = [0; 8];
+	tx_depth[0] = ::core::cmp::max(words(mps0), MIN_TX_DEPTH);
+	let mut out_endpoints = 0;
+	let mut max_out = mps0;
+	for ep in endpoints {
+		if ep.is_in() {
+			let fifo = ep.fifo as usize;
+			assert!(fifo < NUM_ENDPOINTS as usize);
+			let depth = ::core::cmp::max(2 * words(ep.mps), MIN_TX_DEPTH);
+			tx_depth[fifo] = ::core::cmp::max(tx_depth[fifo], depth);
+		} else {
+			out_endpoints += 1;
+			max_out = ::core::cmp::max(max_out, ep.mps);
+		}
+	}
+	// SETUP packets, status entries of two packets, transfer complete entries and the
+	// global OUT NAK entry
+	let rx_depth = 13 + 2 * (words(max_out) + 1) + 2 * (out_endpoints + 1) + 1;
+
+	let mut layout = Layout { rx_depth: rx_depth, tx: [(0, 0); 8] };
+	let mut next = rx_depth as u32;
+	for (fifo, depth) in tx_depth.iter().enumerate() {
+		if *depth > 0 {
+			layout.tx[fifo] = (next as u16, *depth);
+			next += *depth as u32;
+		}
+	}
+	if next + DMA_WORDS as u32 > RAM_WORDS as u32 {
+		return Err(UsbError::FifoRamExhausted { words: next as u16 });
+	}
+	Ok(layout)
+}
+
+// Programs `layout` and flushes the TX FIFOs it has, no transfer may be in progress.
+pub fn program(hw: &mut Hardware, layout: &Layout) {
+	hw.global().otg_hs_grxfsiz.update(|r| r.set_rxfd(layout.rx_depth));
+	let (start, depth) = layout.tx[0];
+	// DIEPTXF0 in device mode
+	hw.global().otg_hs_hnptxfsiz_host.update(|r| {
+		r.set_nptxfsa(start);
+		r.set_nptxfd(depth);
+	});
+	for n in 1..NUM_ENDPOINTS {
+		let (start, depth) = layout.tx[n as usize];
+		if depth > 0 {
+			dieptxf!(hw.global(), n, |r| r.update(|r| {
+				r.set_ineptxsa(start);
+				r.set_ineptxfd(depth);
+			}));
+		}
+	}
+	for n in 0..NUM_ENDPOINTS {
+		if layout.tx[n as usize].1 > 0 {
+			hw.flush_tx(n);
+		}
+	}
+}
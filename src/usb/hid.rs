@@ -0,0 +1,666 @@
+// HID 1.11 functions: the HID and report descriptors, the class requests on endpoint 0
+// and the reports of the interrupt endpoints. A HID interface in `descriptor::TREE` has a
+// `hid` entry with the function that writes its report descriptor, an interrupt IN
+// endpoint and optionally an interrupt OUT endpoint for output reports. Its feature
+// reports start out as `FEATURES` lists them.
+
+use core::fmt;
+use super::control::{Reply, Setup};
+use super::descriptor::{self, Descriptor};
+use super::digitizer;
+
+// bInterfaceClass, bInterfaceSubClass and bInterfaceProtocol
+pub const CLASS_HID : u8 = 0x03;
+pub const SUBCLASS_NONE : u8 = 0x00;
+pub const SUBCLASS_BOOT : u8 = 0x01;
+pub const PROTO_NONE : u8 = 0x00;
+pub const PROTO_KEYBOARD : u8 = 0x01;
+pub const PROTO_MOUSE : u8 = 0x02;
+
+// bDescriptorType
+pub const HID : u8 = 0x21;
+pub const REPORT : u8 = 0x22;
+
+// bRequest
+pub const GET_REPORT : u8 = 0x01;
+pub const GET_IDLE : u8 = 0x02;
+pub const GET_PROTOCOL : u8 = 0x03;
+pub const SET_REPORT : u8 = 0x09;
+pub const SET_IDLE : u8 = 0x0a;
+pub const SET_PROTOCOL : u8 = 0x0b;
+
+// report types in the high byte of wValue of GET_REPORT and SET_REPORT
+pub const INPUT : u8 = 1;
+pub const OUTPUT : u8 = 2;
+pub const FEATURE : u8 = 3;
+
+// GET_PROTOCOL and SET_PROTOCOL
+pub const BOOT_PROTOCOL : u8 = 0;
+pub const REPORT_PROTOCOL : u8 = 1;
+
+// HID 1.11
+pub const BCD_HID : u16 = 0x0111;
+
+// longest report of any type, one full speed interrupt packet
+pub const REPORT_LEN : usize = 64;
+// HID interfaces in the configuration
+pub const MAX_FUNCTIONS : usize = 2;
+// feature reports of a HID interface
+pub const MAX_FEATURES : usize = 2;
+// SOF interrupts per ms, one per microframe at high speed
+pub const FRAMES_PER_MS : u32 = 8;
+
+// initial feature reports of the HID interfaces that have any
+pub const FEATURES : &'static [(u8, &'static [&'static [u8]])] = &[
+	(digitizer::INTERFACE, digitizer::FEATURES),
+];
+
+// usage pages
+pub const GENERIC_DESKTOP : u16 = 0x01;
+pub const KEYBOARD : u16 = 0x07;
+pub const LED : u16 = 0x08;
+pub const BUTTON : u16 = 0x09;
+pub const DIGITIZER : u16 = 0x0d;
+
+// collection types
+pub const PHYSICAL : u8 = 0x00;
+pub const APPLICATION : u8 = 0x01;
+pub const LOGICAL : u8 = 0x02;
+
+// bits of input, output and feature items, the cleared bit is the default: data,
+// array, absolute
+pub const CONSTANT : u32 = 0x01;
+pub const VARIABLE : u32 = 0x02;
+pub const RELATIVE : u32 = 0x04;
+pub const WRAP : u32 = 0x08;
+pub const NON_LINEAR : u32 = 0x10;
+pub const NO_PREFERRED : u32 = 0x20;
+pub const NULL_STATE : u32 = 0x40;
+pub const VOLATILE : u32 = 0x80;
+
+// Writes a report descriptor item by item. The data of every item takes 1, 2 or 4 bytes,
+// whatever its value needs. Like `descriptor::Builder` it keeps counting past the end of
+// the buffer, so building into an empty buffer measures a report descriptor. It also
+// adds up the input items of one report ID, to measure that input report.
+pub struct Report<'a> {
+	buf: &'a mut [u8],
+	len: usize,
+	// current report size, count and ID
+	size: u32,
+	count: u32,
+	id: u8,
+	// bits of the input items of report `input_id`
+	input_id: u8,
+	input_bits: u32,
+}
+
+impl<'a> Report<'a> {
+	pub fn new(buf: &'a mut [u8]) -> Report<'a> {
+		Report { buf: buf, len: 0, size: 0, count: 0, id: 0, input_id: 0, input_bits: 0 }
+	}
+
+	// main items
+	pub fn input(&mut self, flags: u32) -> &mut Report<'a> {
+		if self.id == self.input_id {
+			self.input_bits += self.size * self.count;
+		}
+		self.unsigned(0x80, flags)
+	}
+
+	pub fn output(&mut self, flags: u32) -> &mut Report<'a> {
+		self.unsigned(0x90, flags)
+	}
+
+	pub fn feature(&mut self, flags: u32) -> &mut Report<'a> {
+		self.unsigned(0xb0, flags)
+	}
+
+	pub fn collection(&mut self, kind: u8) -> &mut Report<'a> {
+		self.unsigned(0xa0, kind as u32)
+	}
+
+	pub fn end_collection(&mut self) -> &mut Report<'a> {
+		self.put8(0xc0);
+		self
+	}
+
+	// global items
+	pub fn usage_page(&mut self, page: u16) -> &mut Report<'a> {
+		self.unsigned(0x04, page as u32)
+	}
+
+	pub fn logical_minimum(&mut self, value: i32) -> &mut Report<'a> {
+		self.signed(0x14, value)
+	}
+
+	pub fn logical_maximum(&mut self, value: i32) -> &mut Report<'a> {
+		self.signed(0x24, value)
+	}
+
+	pub fn physical_minimum(&mut self, value: i32) -> &mut Report<'a> {
+		self.signed(0x34, value)
+	}
+
+	pub fn physical_maximum(&mut self, value: i32) -> &mut Report<'a> {
+		self.signed(0x44, value)
+	}
+
+	pub fn unit_exponent(&mut self, exponent: i32) -> &mut Report<'a> {
+		self.signed(0x54, exponent)
+	}
+
+	pub fn unit(&mut self, unit: u32) -> &mut Report<'a> {
+		self.unsigned(0x64, unit)
+	}
+
+	// in bits
+	pub fn report_size(&mut self, bits: u32) -> &mut Report<'a> {
+		self.size = bits;
+		self.unsigned(0x74, bits)
+	}
+
+	pub fn report_id(&mut self, id: u8) -> &mut Report<'a> {
+		self.id = id;
+		self.unsigned(0x84, id as u32)
+	}
+
+	pub fn report_count(&mut self, count: u32) -> &mut Report<'a> {
+		self.count = count;
+		self.unsigned(0x94, count)
+	}
+
+	// local items
+	pub fn usage(&mut self, usage: u16) -> &mut Report<'a> {
+		self.unsigned(0x08, usage as u32)
+	}
+
+	pub fn usage_minimum(&mut self, usage: u16) -> &mut Report<'a> {
+		self.unsigned(0x18, usage as u32)
+	}
+
+	pub fn usage_maximum(&mut self, usage: u16) -> &mut Report<'a> {
+		self.unsigned(0x28, usage as u32)
+	}
+
+	// Length of everything written, None if it did not fit.
+	pub fn finish(&self) -> Option<usize> {
+		if self.len > self.buf.len() { None } else { Some(self.len) }
+	}
+
+	fn unsigned(&mut self, prefix: u8, value: u32) -> &mut Report<'a> {
+		let size = if value > 0xffff { 4 } else if value > 0xff { 2 } else { 1 };
+		self.item(prefix, value, size)
+	}
+
+	fn signed(&mut self, prefix: u8, value: i32) -> &mut Report<'a> {
+		let size = if value < -0x8000 || value > 0x7fff {
+			4
+		} else if value < -0x80 || value > 0x7f {
+			2
+		} else {
+			1
+		};
+		self.item(prefix, value as u32, size)
+	}
+
+	// bSize is 1, 2 or 3 for 1, 2 or 4 data bytes
+	fn item(&mut self, prefix: u8, data: u32, size: usize) -> &mut Report<'a> {
+		self.put8(prefix | if size == 4 { 3 } else { size as u8 });
+		for i in 0..size {
+			self.put8((data >> (i*8)) as u8);
+		}
+		self
+	}
+
+	fn put8(&mut self, value: u8) {
+		if self.len < self.buf.len() {
+			self.buf[self.len] = value;
+		}
+		self.len += 1;
+	}
+}
+
+// The function writing the report descriptor of a HID interface. Comparing and printing
+// go by address, which the derives cannot do for a function taking a reference.
+pub struct ReportDescriptor(pub fn(&mut Report));
+
+impl ReportDescriptor {
+	pub fn write(&self, buf: &mut [u8]) -> Option<usize> {
+		let mut report = Report::new(buf);
+		(self.0)(&mut report);
+		report.finish()
+	}
+
+	pub fn len(&self) -> usize {
+		let mut report = Report::new(&mut []);
+		(self.0)(&mut report);
+		report.len
+	}
+
+	// Length of input report `id` in bytes, with the ID in front unless it is 0.
+	pub fn input_len(&self, id: u8) -> usize {
+		let mut report = Report::new(&mut []);
+		report.input_id = id;
+		(self.0)(&mut report);
+		(report.input_bits as usize + 7) / 8 + if id != 0 { 1 } else { 0 }
+	}
+}
+
+impl Copy for ReportDescriptor {}
+
+impl Clone for ReportDescriptor {
+	fn clone(&self) -> ReportDescriptor {
+		*self
+	}
+}
+
+impl PartialEq for ReportDescriptor {
+	fn eq(&self, other: &ReportDescriptor) -> bool {
+		self.0 as usize == other.0 as usize
+	}
+}
+
+impl fmt::Debug for ReportDescriptor {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "ReportDescriptor({:#x})", self.0 as usize)
+	}
+}
+
+// A report and its length
+#[derive(Copy, Clone)]
+struct Buf {
+	data: [u8; REPORT_LEN],
+	len: usize,
+}
+
+impl Buf {
+	fn new() -> Buf {
+		Buf { data: [0; REPORT_LEN], len: 0 }
+	}
+
+	fn set(&mut self, data: &[u8]) {
+		let len = ::core::cmp::min(data.len(), REPORT_LEN);
+		self.data[..len].copy_from_slice(&data[..len]);
+		self.len = len;
+	}
+
+	fn get(&self) -> &[u8] {
+		&self.data[..self.len]
+	}
+
+	// ID of a report that starts with one, the host asks for 0 without report IDs
+	fn matches(&self, id: u8) -> bool {
+		id == 0 || (self.len > 0 && self.data[0] == id)
+	}
+}
+
+// A HID interface of the configuration and the state the host set on it.
+#[derive(Copy, Clone)]
+pub struct Hid {
+	interface: u8,
+	in_ep: u8,
+	out_ep: Option<u8>,
+	boot: bool,
+	report: Option<ReportDescriptor>,
+	// in units of 4 ms, 0 is only on change
+	idle: u8,
+	// microframes since the last input report went out
+	since_input: u32,
+	protocol: u8,
+	// last input report sent, answers GET_REPORT
+	input: Buf,
+	// last output report from the host and whether it was read yet
+	output: Buf,
+	output_new: bool,
+	// feature reports from `FEATURES`, the host may change them
+	initial_features: &'static [&'static [u8]],
+	features: [Buf; MAX_FEATURES],
+}
+
+impl Hid {
+	fn new(interface: u8, boot: bool) -> Hid {
+		Hid {
+			interface: interface,
+			in_ep: 0,
+			out_ep: None,
+			boot: boot,
+			report: None,
+			idle: 0,
+			since_input: 0,
+			protocol: REPORT_PROTOCOL,
+			input: Buf::new(),
+			output: Buf::new(),
+			output_new: false,
+			initial_features: &[],
+			features: [Buf::new(); MAX_FEATURES],
+		}
+	}
+
+	// Feature reports `features`, the ones beyond `MAX_FEATURES` are left out.
+	fn set_features(&mut self, features: &'static [&'static [u8]]) {
+		self.initial_features = features;
+		for (buf, feature) in self.features.iter_mut().zip(features.iter()) {
+			buf.set(feature);
+		}
+	}
+
+	fn find_feature(&mut self, id: u8) -> Option<&mut Buf> {
+		self.features.iter_mut().find(|f| f.len > 0 && f.matches(id))
+	}
+
+	// bus reset or new configuration, the endpoints and the report descriptor stay, the
+	// feature reports go back to the initial ones
+	pub fn reset(&mut self) {
+		let (in_ep, out_ep, report, features) = (self.in_ep, self.out_ep, self.report, self.initial_features);
+		*self = Hid::new(self.interface, self.boot);
+		self.in_ep = in_ep;
+		self.out_ep = out_ep;
+		self.report = report;
+		self.set_features(features);
+	}
+
+	pub fn interface(&self) -> u8 {
+		self.interface
+	}
+
+	pub fn in_ep(&self) -> u8 {
+		self.in_ep
+	}
+
+	pub fn out_ep(&self) -> Option<u8> {
+		self.out_ep
+	}
+
+	// report rate while nothing changes in ms the host asked for, 0 for only on change
+	pub fn idle_ms(&self) -> u32 {
+		self.idle as u32 * 4
+	}
+
+	// One microframe went by. Once the idle rate passed without a new input report the
+	// last one is due again (HID 1.11 7.2.4), it is copied to `buf` and its length
+	// returned. Before the first report there is nothing to repeat.
+	pub fn frame(&mut self, buf: &mut [u8]) -> Option<usize> {
+		if self.idle == 0 || self.input.len == 0 {
+			return None;
+		}
+		self.since_input = self.since_input.saturating_add(1);
+		if self.since_input < self.idle_ms() * FRAMES_PER_MS {
+			return None;
+		}
+		let len = self.input.len;
+		buf[..len].copy_from_slice(self.input.get());
+		Some(len)
+	}
+
+	pub fn protocol(&self) -> u8 {
+		self.protocol
+	}
+
+	// last output report from the host, empty before the first one
+	pub fn output(&self) -> &[u8] {
+		self.output.get()
+	}
+
+	// Output report the host sent since the last call.
+	pub fn take_output(&mut self) -> Option<&[u8]> {
+		if !self.output_new {
+			return None;
+		}
+		self.output_new = false;
+		Some(self.output.get())
+	}
+
+	// Feature report `id` as the host last set it, 0 for the only one without report IDs.
+	pub fn feature(&mut self, id: u8) -> Option<&[u8]> {
+		self.find_feature(id).map(|f| f.get())
+	}
+
+	// the input report `report` went out on the IN endpoint
+	pub fn input_sent(&mut self, report: &[u8]) {
+		self.input.set(report);
+		self.since_input = 0;
+	}
+
+	// Output report from the OUT endpoint or a SET_REPORT.
+	pub fn output_received(&mut self, report: &[u8]) {
+		self.output.set(report);
+		self.output_new = true;
+	}
+
+	// Class requests to the interface.
+	pub fn request(&mut self, setup: &Setup, buf: &mut [u8]) -> Reply {
+		let (report_type, id) = ((setup.value >> 8) as u8, setup.value as u8);
+		match setup.request {
+			GET_REPORT if setup.is_in() => {
+				let report = match report_type {
+					INPUT if self.input.len > 0 && self.input.matches(id) => &self.input,
+					// nothing sent yet, the report is all zeros
+					INPUT => {
+						let len = match self.report.map(|r| r.input_len(id)) {
+							Some(len) if len > 0 && len <= REPORT_LEN => len,
+							_ => return Reply::Stall,
+						};
+						for byte in buf[..len].iter_mut() {
+							*byte = 0;
+						}
+						if id != 0 {
+							buf[0] = id;
+						}
+						return Reply::Data(len);
+					},
+					FEATURE => match self.find_feature(id) {
+						Some(feature) => feature,
+						None => return Reply::Stall,
+					},
+					_ => return Reply::Stall,
+				};
+				let len = report.len;
+				buf[..len].copy_from_slice(report.get());
+				Reply::Data(len)
+			},
+			// the report follows in the data stage
+			SET_REPORT if !setup.is_in() && setup.length as usize <= REPORT_LEN => {
+				match report_type {
+					OUTPUT | FEATURE => Reply::Ack,
+					_ => Reply::Stall,
+				}
+			},
+			GET_IDLE if setup.is_in() => {
+				buf[0] = self.idle;
+				Reply::Data(1)
+			},
+			// one rate for all reports, `frame` repeats them
+			SET_IDLE if setup.length == 0 => {
+				self.idle = (setup.value >> 8) as u8;
+				Reply::Ack
+			},
+			GET_PROTOCOL if setup.is_in() && self.boot => {
+				buf[0] = self.protocol;
+				Reply::Data(1)
+			},
+			SET_PROTOCOL if setup.length == 0 && self.boot && setup.value <= 1 => {
+				self.protocol = setup.value as u8;
+				Reply::Ack
+			},
+			_ => Reply::Stall,
+		}
+	}
+
+	// Data stage of a SET_REPORT `request` accepted.
+	pub fn data_out(&mut self, setup: &Setup, data: &[u8]) -> Reply {
+		match (setup.request, (setup.value >> 8) as u8) {
+			(SET_REPORT, OUTPUT) => {
+				self.output_received(data);
+				Reply::Ack
+			},
+			(SET_REPORT, FEATURE) => {
+				match self.find_feature(setup.value as u8) {
+					Some(feature) => {
+						feature.set(data);
+						Reply::Ack
+					},
+					None => Reply::Stall,
+				}
+			},
+			_ => Reply::Stall,
+		}
+	}
+}
+
+// The HID interfaces of the configuration tree `tree`, in the order of the tree.
+// `descriptors!` makes sure there are no more than `MAX_FUNCTIONS`, the rest is left out.
+pub fn functions(tree: &[Descriptor]) -> [Option<Hid>; MAX_FUNCTIONS] {
+	let mut functions = [None; MAX_FUNCTIONS];
+	let mut count = 0;
+	let mut current = None;
+	for desc in tree {
+		match *desc {
+			Descriptor::Interface { number, class, subclass, .. } => {
+				current = None;
+				if class == CLASS_HID && count < MAX_FUNCTIONS {
+					let mut hid = Hid::new(number, subclass == SUBCLASS_BOOT);
+					if let Some(&(_, features)) = FEATURES.iter().find(|f| f.0 == number) {
+						hid.set_features(features);
+					}
+					functions[count] = Some(hid);
+					current = Some(count);
+					count += 1;
+				}
+			},
+			Descriptor::Hid { report, .. } => {
+				if let Some(i) = current {
+					functions[i].as_mut().unwrap().report = Some(report);
+				}
+			},
+			Descriptor::Endpoint { address, attributes, .. } if attributes & 0x3 == descriptor::INTERRUPT => {
+				if let Some(i) = current {
+					let hid = functions[i].as_mut().unwrap();
+					if address & 0x80 != 0 {
+						hid.in_ep = address;
+					} else {
+						hid.out_ep = Some(address);
+					}
+				}
+			},
+			_ => (),
+		}
+	}
+	functions
+}
+
+// The HID function of interface `interface`.
+pub fn find(functions: &mut [Option<Hid>], interface: u8) -> Option<&mut Hid> {
+	functions.iter_mut().filter_map(|f| f.as_mut()).find(|f| f.interface == interface)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::control::Setup;
+	use super::super::{descriptor, digitizer, keyboard};
+
+	fn request(hid: &mut Hid, setup: [u8; 8], buf: &mut [u8]) -> Reply {
+		hid.request(&Setup::parse(&setup), buf)
+	}
+
+	fn function(interface: u8) -> Hid {
+		let mut functions = functions(descriptor::TREE);
+		*find(&mut functions, interface).unwrap()
+	}
+
+	#[test]
+	fn input_report_lengths() {
+		assert_eq!(ReportDescriptor(keyboard::report).input_len(0), keyboard::REPORT_LEN);
+		assert_eq!(ReportDescriptor(digitizer::report).input_len(digitizer::TOUCH_REPORT_ID),
+			digitizer::REPORT_LEN);
+		assert_eq!(ReportDescriptor(digitizer::report).input_len(digitizer::MAX_COUNT_REPORT_ID), 1);
+		assert_eq!(ReportDescriptor(digitizer::report).input_len(digitizer::MOUSE_REPORT_ID),
+			digitizer::MOUSE_REPORT_LEN);
+	}
+
+	#[test]
+	fn get_input_report_before_the_first_one_is_zero() {
+		let mut hid = function(keyboard::INTERFACE);
+		let mut buf = [0xff; REPORT_LEN];
+		let get = [0xa1, GET_REPORT, 0, INPUT, keyboard::INTERFACE, 0, 8, 0];
+		assert_eq!(request(&mut hid, get, &mut buf), Reply::Data(keyboard::REPORT_LEN));
+		assert_eq!(buf[..keyboard::REPORT_LEN], [0; 8]);
+
+		hid.input_sent(&[0, 0, 4, 0, 0, 0, 0, 0]);
+		assert_eq!(request(&mut hid, get, &mut buf), Reply::Data(keyboard::REPORT_LEN));
+		assert_eq!(buf[..keyboard::REPORT_LEN], [0, 0, 4, 0, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn get_input_report_with_an_id() {
+		let mut hid = function(digitizer::INTERFACE);
+		let mut buf = [0xff; REPORT_LEN];
+		let get = [0xa1, GET_REPORT, digitizer::TOUCH_REPORT_ID, INPUT, digitizer::INTERFACE, 0, 64, 0];
+		assert_eq!(request(&mut hid, get, &mut buf), Reply::Data(digitizer::REPORT_LEN));
+		assert_eq!(buf[0], digitizer::TOUCH_REPORT_ID);
+		assert!(buf[1..digitizer::REPORT_LEN].iter().all(|b| *b == 0));
+	}
+
+	#[test]
+	fn feature_reports_start_out_as_listed() {
+		let mut hid = function(digitizer::INTERFACE);
+		let mut buf = [0; REPORT_LEN];
+		let get = [0xa1, GET_REPORT, digitizer::MAX_COUNT_REPORT_ID, FEATURE, digitizer::INTERFACE, 0, 64, 0];
+		assert_eq!(request(&mut hid, get, &mut buf), Reply::Data(2));
+		assert_eq!(buf[..2], [digitizer::MAX_COUNT_REPORT_ID, digitizer::MAX_CONTACTS as u8]);
+
+		// no feature reports on the keyboard
+		let mut hid = function(keyboard::INTERFACE);
+		let get = [0xa1, GET_REPORT, 0, FEATURE, keyboard::INTERFACE, 0, 64, 0];
+		assert_eq!(request(&mut hid, get, &mut buf), Reply::Stall);
+	}
+
+	#[test]
+	fn set_feature_report_lasts_until_reset() {
+		let mut hid = function(digitizer::INTERFACE);
+		let id = digitizer::CONFIGURATION_REPORT_ID;
+		let set = Setup::parse(&[0x21, SET_REPORT, id, FEATURE, digitizer::INTERFACE, 0, 3, 0]);
+		assert_eq!(hid.data_out(&set, &[id, digitizer::MODE_MULTI_INPUT, 0]), Reply::Ack);
+		assert_eq!(hid.feature(id), Some(&[id, digitizer::MODE_MULTI_INPUT, 0][..]));
+		// the contact count maximum stays
+		assert_eq!(hid.feature(digitizer::MAX_COUNT_REPORT_ID),
+			Some(&[digitizer::MAX_COUNT_REPORT_ID, digitizer::MAX_CONTACTS as u8][..]));
+
+		hid.reset();
+		assert_eq!(hid.feature(id), Some(&[id, digitizer::MODE_MOUSE, 0][..]));
+
+		// a report ID the interface has no feature report with
+		let set = Setup::parse(&[0x21, SET_REPORT, 9, FEATURE, digitizer::INTERFACE, 0, 2, 0]);
+		assert_eq!(hid.data_out(&set, &[9, 1]), Reply::Stall);
+	}
+
+	#[test]
+	fn idle_rate_repeats_the_last_report() {
+		let mut hid = function(keyboard::INTERFACE);
+		let mut buf = [0; REPORT_LEN];
+		assert_eq!(request(&mut hid, [0x21, SET_IDLE, 0, 125, keyboard::INTERFACE, 0, 0, 0], &mut buf), Reply::Ack);
+		assert_eq!(hid.idle_ms(), 500);
+		assert_eq!(request(&mut hid, [0xa1, GET_IDLE, 0, 0, keyboard::INTERFACE, 0, 1, 0], &mut buf), Reply::Data(1));
+		assert_eq!(buf[0], 125);
+
+		// nothing sent yet
+		assert_eq!(hid.frame(&mut buf), None);
+		let report = [0, 0, 4, 0, 0, 0, 0, 0];
+		hid.input_sent(&report);
+		for _ in 1..500 * FRAMES_PER_MS {
+			assert_eq!(hid.frame(&mut buf), None);
+		}
+		assert_eq!(hid.frame(&mut buf), Some(keyboard::REPORT_LEN));
+		assert_eq!(buf[..keyboard::REPORT_LEN], report);
+		// still due while the endpoint is busy, a sent report starts over
+		assert_eq!(hid.frame(&mut buf), Some(keyboard::REPORT_LEN));
+		hid.input_sent(&report);
+		assert_eq!(hid.frame(&mut buf), None);
+
+		// only on change
+		assert_eq!(request(&mut hid, [0x21, SET_IDLE, 0, 0, keyboard::INTERFACE, 0, 0, 0], &mut buf), Reply::Ack);
+		for _ in 0..500 * FRAMES_PER_MS {
+			assert_eq!(hid.frame(&mut buf), None);
+		}
+	}
+}
@@ -0,0 +1,267 @@
+use board::otg_hs_global::OtgHsGlobal;
+use board::otg_hs_device::OtgHsDevice;
+
+// FIFO window of endpoint 0, the windows of the other endpoints follow every 0x1000 bytes
+const FIFO_BASE : usize = 0x4004_1000;
+const FIFO_STRIDE : usize = 0x1000;
+// 96 bit unique device ID, factory programmed (RM0385, device electronic signature)
+const UID_BASE : usize = 0x1FF0_F420;
+// DIEPINTx/DOEPINTx.EPDISD
+const EPDISD : u32 = 1 << 1;
+// register reads to wait for INEPNE or EPDISD, well above the microframe the core may
+// take at 216 MHz
+const DISABLE_RETRIES : u32 = 100_000;
+
+// Write-1-to-clear status registers the driver acknowledges
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Status {
+	Gintsts,
+	Gotgint,
+	Diepint(u8),
+	Doepint(u8),
+}
+
+// Everything the driver touches on the OTG HS core. Plain configuration registers are
+// accessed through the register blocks, everything with side effects on read or write
+// (receive status queue, FIFOs, w1c status registers) goes through its own method so
+// that a mock can model it.
+pub trait Hardware {
+	fn global(&mut self) -> &mut OtgHsGlobal;
+	fn device(&mut self) -> &mut OtgHsDevice;
+	// read and pop the top of the receive status queue (GRXSTSP)
+	fn pop_rx_status(&mut self) -> u32;
+	// the RX FIFO is shared by all OUT endpoints
+	fn read_fifo(&mut self) -> u32;
+	fn write_fifo(&mut self, ep: u8, word: u32);
+	fn acknowledge(&mut self, reg: Status, bits: u32);
+	// core soft reset (GRSTCTL.CSRST), flushes the FIFOs and restarts the state machines
+	fn soft_reset(&mut self);
+	// discards the contents of TX FIFO `fifo` (GRSTCTL.TXFFLSH)
+	fn flush_tx(&mut self, fifo: u8);
+	// sets or clears the global OUT NAK (DCTL.SGONAK/CGONAK), GINTSTS.BOUTNAKEFF tells when
+	// it is in effect
+	fn global_out_nak(&mut self, nak: bool);
+	// NAKs IN endpoint `ep` 1 to 7, then disables it and waits for EPDISD, false if it
+	// does not come. The TX FIFO is left to the caller to flush.
+	fn disable_in(&mut self, ep: u8) -> bool;
+	// disables OUT endpoint `ep` 1 to 7 and waits for EPDISD, false if it does not come.
+	// The global OUT NAK has to be in effect.
+	fn disable_out(&mut self, ep: u8) -> bool;
+	// unique device ID of the chip, lowest word first
+	fn unique_id(&mut self) -> [u32; 3];
+}
+
+// Binds `$r` to the status register `$reg` and evaluates to `$body` evaluated with it,
+// None if there is no such register.
+macro_rules! status_reg {
+	($global:expr, $device:expr, $reg:expr, |$r:ident| $body:expr) => {
+		match $reg {
+			Status::Gintsts => { let $r = &mut $global.otg_hs_gintsts; Some($body) }
+			Status::Gotgint => { let $r = &mut $global.otg_hs_gotgint; Some($body) }
+			Status::Diepint(0) => { let $r = &mut $device.otg_hs_diepint0; Some($body) }
+			Status::Diepint(1) => { let $r = &mut $device.otg_hs_diepint1; Some($body) }
+			Status::Diepint(2) => { let $r = &mut $device.otg_hs_diepint2; Some($body) }
+			Status::Diepint(3) => { let $r = &mut $device.otg_hs_diepint3; Some($body) }
+			Status::Diepint(4) => { let $r = &mut $device.otg_hs_diepint4; Some($body) }
+			Status::Diepint(5) => { let $r = &mut $device.otg_hs_diepint5; Some($body) }
+			Status::Diepint(6) => { let $r = &mut $device.otg_hs_diepint6; Some($body) }
+			Status::Diepint(7) => { let $r = &mut $device.otg_hs_diepint7; Some($body) }
+			Status::Doepint(0) => { let $r = &mut $device.otg_hs_doepint0; Some($body) }
+			Status::Doepint(1) => { let $r = &mut $device.otg_hs_doepint1; Some($body) }
+			Status::Doepint(2) => { let $r = &mut $device.otg_hs_doepint2; Some($body) }
+			Status::Doepint(3) => { let $r = &mut $device.otg_hs_doepint3; Some($body) }
+			Status::Doepint(4) => { let $r = &mut $device.otg_hs_doepint4; Some($body) }
+			Status::Doepint(5) => { let $r = &mut $device.otg_hs_doepint5; Some($body) }
+			Status::Doepint(6) => { let $r = &mut $device.otg_hs_doepint6; Some($body) }
+			Status::Doepint(7) => { let $r = &mut $device.otg_hs_doepint7; Some($body) }
+			Status::Diepint(_) | Status::Doepint(_) => None,
+		}
+	}
+}
+
+// Binds `$r` to the register of endpoint `$ep` out of the registers `[$r1, .., $r7]` of
+// endpoints 1 to 7 and evaluates to `$body` evaluated with it, None for any other
+// endpoint. Endpoint 0 has registers of its own.
+macro_rules! ep_reg {
+	($block:expr, $ep:expr, [$r1:ident, $r2:ident, $r3:ident, $r4:ident, $r5:ident, $r6:ident, $r7:ident], |$r:ident| $body:expr) => {
+		match $ep {
+			1 => { let $r = &mut $block.$r1; Some($body) }
+			2 => { let $r = &mut $block.$r2; Some($body) }
+			3 => { let $r = &mut $block.$r3; Some($body) }
+			4 => { let $r = &mut $block.$r4; Some($body) }
+			5 => { let $r = &mut $block.$r5; Some($body) }
+			6 => { let $r = &mut $block.$r6; Some($body) }
+			7 => { let $r = &mut $block.$r7; Some($body) }
+			_ => None,
+		}
+	}
+}
+
+macro_rules! diepctl {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_diepctl1, otg_hs_diepctl2, otg_hs_diepctl3, otg_hs_diepctl4,
+			otg_hs_diepctl5, otg_hs_diepctl6, otg_hs_diepctl7], |$r| $body)
+	}
+}
+
+macro_rules! doepctl {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_doepctl1, otg_hs_doepctl2, otg_hs_doepctl3, otg_hs_doepctl4,
+			otg_hs_doepctl5, otg_hs_doepctl6, otg_hs_doepctl7], |$r| $body)
+	}
+}
+
+macro_rules! dieptsiz {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_dieptsiz1, otg_hs_dieptsiz2, otg_hs_dieptsiz3, otg_hs_dieptsiz4,
+			otg_hs_dieptsiz5, otg_hs_dieptsiz6, otg_hs_dieptsiz7], |$r| $body)
+	}
+}
+
+macro_rules! diepint {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_diepint1, otg_hs_diepint2, otg_hs_diepint3, otg_hs_diepint4,
+			otg_hs_diepint5, otg_hs_diepint6, otg_hs_diepint7], |$r| $body)
+	}
+}
+
+macro_rules! diepdma {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_diepdma1, otg_hs_diepdma2, otg_hs_diepdma3, otg_hs_diepdma4,
+			otg_hs_diepdma5, otg_hs_diepdma6, otg_hs_diepdma7], |$r| $body)
+	}
+}
+
+macro_rules! doeptsiz {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_doeptsiz1, otg_hs_doeptsiz2, otg_hs_doeptsiz3, otg_hs_doeptsiz4,
+			otg_hs_doeptsiz5, otg_hs_doeptsiz6, otg_hs_doeptsiz7], |$r| $body)
+	}
+}
+
+macro_rules! doepint {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_doepint1, otg_hs_doepint2, otg_hs_doepint3, otg_hs_doepint4,
+			otg_hs_doepint5, otg_hs_doepint6, otg_hs_doepint7], |$r| $body)
+	}
+}
+
+macro_rules! doepdma {
+	($device:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($device, $ep, [otg_hs_doepdma1, otg_hs_doepdma2, otg_hs_doepdma3, otg_hs_doepdma4,
+			otg_hs_doepdma5, otg_hs_doepdma6, otg_hs_doepdma7], |$r| $body)
+	}
+}
+
+macro_rules! dieptxf {
+	($global:expr, $ep:expr, |$r:ident| $body:expr) => {
+		ep_reg!($global, $ep, [otg_hs_dieptxf1, otg_hs_dieptxf2, otg_hs_dieptxf3, otg_hs_dieptxf4,
+			otg_hs_dieptxf5, otg_hs_dieptxf6, otg_hs_dieptxf7], |$r| $body)
+	}
+}
+
+pub struct Stm32f7 {
+	global: &'static mut OtgHsGlobal,
+	device: &'static mut OtgHsDevice,
+}
+
+impl Stm32f7 {
+	pub fn new(global: &'static mut OtgHsGlobal, device: &'static mut OtgHsDevice) -> Stm32f7 {
+		Stm32f7 { global: global, device: device }
+	}
+
+	// Polls `done` until it holds, false if it still does not after DISABLE_RETRIES reads.
+	fn wait<F: FnMut(&mut Stm32f7) -> bool>(&mut self, mut done: F) -> bool {
+		(0..DISABLE_RETRIES).any(|_| done(self))
+	}
+}
+
+impl Hardware for Stm32f7 {
+	fn global(&mut self) -> &mut OtgHsGlobal {
+		self.global
+	}
+
+	fn device(&mut self) -> &mut OtgHsDevice {
+		self.device
+	}
+
+	fn pop_rx_status(&mut self) -> u32 {
+		self.global.otg_hs_grxstsp_host.read().bits
+	}
+
+	fn read_fifo(&mut self) -> u32 {
+		unsafe { ::core::ptr::read_volatile(FIFO_BASE as *const u32) }
+	}
+
+	fn write_fifo(&mut self, ep: u8, word: u32) {
+		assert!(ep < 8);
+		let ptr = (FIFO_BASE + FIFO_STRIDE * ep as usize) as *mut u32;
+		unsafe { ::core::ptr::write_volatile(ptr, word); }
+	}
+
+	fn acknowledge(&mut self, reg: Status, bits: u32) {
+		status_reg!(self.global, self.device, reg, |r| {
+			let mut value = r.read();
+			value.bits = bits;
+			r.write(value);
+		});
+	}
+
+	fn soft_reset(&mut self) {
+		while ! self.global.otg_hs_grstctl.read().ahbidl() {};
+		self.global.otg_hs_grstctl.update(|r| r.set_csrst(true));
+		while self.global.otg_hs_grstctl.read().csrst() {};
+	}
+
+	fn flush_tx(&mut self, fifo: u8) {
+		while ! self.global.otg_hs_grstctl.read().ahbidl() {};
+		self.global.otg_hs_grstctl.update(|r| {
+			r.set_txfnum(fifo);
+			r.set_txfflsh(true);
+		});
+		while self.global.otg_hs_grstctl.read().txfflsh() {};
+	}
+
+	fn global_out_nak(&mut self, nak: bool) {
+		self.device.otg_hs_dctl.update(|r| if nak { r.set_sgonak(true) } else { r.set_cgonak(true) });
+	}
+
+	// RM0385, IN endpoint disable
+	fn disable_in(&mut self, ep: u8) -> bool {
+		diepctl!(self.device, ep, |r| r.update(|r| r.set_snak(true)));
+		if !self.wait(|s| diepint!(s.device, ep, |r| r.read().inepne()).unwrap_or(true)) {
+			return false;
+		}
+		diepctl!(self.device, ep, |r| r.update(|r| {
+			r.set_snak(true);
+			r.set_epdis(true);
+		}));
+		if !self.wait(|s| diepint!(s.device, ep, |r| r.read().epdisd()).unwrap_or(true)) {
+			return false;
+		}
+		self.acknowledge(Status::Diepint(ep), EPDISD);
+		true
+	}
+
+	// RM0385, OUT endpoint disable
+	fn disable_out(&mut self, ep: u8) -> bool {
+		doepctl!(self.device, ep, |r| r.update(|r| {
+			r.set_snak(true);
+			r.set_epdis(true);
+		}));
+		if !self.wait(|s| doepint!(s.device, ep, |r| r.read().epdisd()).unwrap_or(true)) {
+			return false;
+		}
+		self.acknowledge(Status::Doepint(ep), EPDISD);
+		true
+	}
+
+	fn unique_id(&mut self) -> [u32; 3] {
+		let uid = UID_BASE as *const u32;
+		unsafe {
+			[::core::ptr::read_volatile(uid),
+				::core::ptr::read_volatile(uid.offset(1)),
+				::core::ptr::read_volatile(uid.offset(2))]
+		}
+	}
+}
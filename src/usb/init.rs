@@ -5,12 +5,13 @@ use board::rcc::Rcc;
 use board::nvic::Nvic;
 use board::otg_hs_device::OtgHsDevice;
 use board::otg_hs_global::OtgHsGlobal;
+use super::error::UsbError;
 
-pub fn init(rcc: &mut Rcc, gpio: &mut Gpio, otg_hs_global: &'static mut OtgHsGlobal, otg_hs_device: &'static mut OtgHsDevice, nvic: &'static mut Nvic) -> Usb {
+pub fn init(rcc: &mut Rcc, gpio: &mut Gpio, otg_hs_global: &'static mut OtgHsGlobal, otg_hs_device: &'static mut OtgHsDevice, nvic: &'static mut Nvic, mode: driver::Mode) -> Result<Usb, UsbError> {
 	rcc.ahb1enr.update(|r| r.set_otghsen(true));
 	rcc.ahb1enr.update(|r| r.set_otghsulpien(true));
 	
-	init_pins(gpio);
+	try!(init_pins(gpio));
 
 	//core init
 	otg_hs_global.otg_hs_gccfg.update(|r| r.set_pwrdwn(false));
@@ -49,12 +50,16 @@ pub fn init(rcc: &mut Rcc, gpio: &mut Gpio, otg_hs_global: &'static mut OtgHsGlo
 
 	otg_hs_device.otg_hs_dctl.update(|r| r.set_sdis(false));
 
-	unsafe { interrupt::init(otg_hs_global,	otg_hs_device, nvic); }
-	Usb {
-	}
+	let hw = hw::Stm32f7::new(otg_hs_global, otg_hs_device);
+	let mut driver = driver::Driver::new(hw);
+	driver.set_mode(mode);
+	interrupt::init(driver, nvic);
+	Ok(Usb {
+		port: serial::Buffers::new(),
+	})
 }
 
-fn init_pins(gpio: &mut Gpio) {
+fn init_pins(gpio: &mut Gpio) -> Result<(), UsbError> {
 	use embedded::interfaces::gpio::Port::*;
 	use embedded::interfaces::gpio::Pin::*;
 	use embedded::interfaces::gpio::{OutputType, OutputSpeed, AlternateFunction, Resistor};
@@ -77,10 +82,8 @@ fn init_pins(gpio: &mut Gpio) {
 			OutputType::PushPull,
 			OutputSpeed::High,
 			Resistor::NoPull) {
-		Ok(_) => (),
-		Err(embedded::interfaces::gpio::Error::PinAlreadyInUse(_)) => {
-			unsafe { asm!("bkpt 0xAB"); }
-		},
+		Ok(_) => Ok(()),
+		Err(embedded::interfaces::gpio::Error::PinAlreadyInUse(_)) => Err(UsbError::PinInUse),
 	}
 }
 
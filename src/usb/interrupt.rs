@@ -1,105 +1,207 @@
+#[cfg(not(feature = "usbip"))]
 use board::nvic::Nvic;
-use board::otg_hs_global::*;
-use board::otg_hs_device::*;
-use collections::vec::Vec;
-use collections::linked_list::LinkedList;
-use alloc::boxed::Box;
-
-static mut GLOBAL: Option<&'static mut OtgHsGlobal> = None;
-static mut DEVICE: Option<&'static mut OtgHsDevice> = None;
-static mut RECEIVE : Option<LinkedList<Packet>> = None;
-
-// DEBUG
-static mut PACKET_IDX : usize = 0;
-static mut PACKET_HIST : [Packet; 128] = [Packet { ep: 0, data: CtlPacket::PLACEHOLDER}; 128];
-static mut IRQ_IDX : usize = 0;
-static mut IRQ_HIST : [(u8, u8); 128] = [(0, 0); 128];
-static mut COUNT : u32 = 0u32;
-static mut LAST_ROW : u16= 0;
-static mut LAST_MASK : u32 = 0;
-static mut GINTSTS_TRIGGERED : u32 = 0u32;
-use ::render;
-use stm32f7::lcd::Lcd;
-static mut LCD: Option<Lcd> = None;
-pub unsafe fn init_debug(lcd_: Lcd) {
-	LCD = Some(lcd_);
-	if let Some(ref mut lcd__)  = LCD {
-		lcd__.clear_screen();
-	}
-}
-// DEBUG END
-
-pub unsafe fn init(global: &'static mut OtgHsGlobal, device: &'static mut OtgHsDevice, 
-		nvic: &mut Nvic) {
-
-	if let Some(ref mut lcd)  = LCD {
-		render::interrupt_debug_init(lcd);
-	}
-
-	GLOBAL = Some(global);
-	DEVICE = Some(device);
-	RECEIVE = Some(LinkedList::new());
-
-	if let Some(ref mut global) = GLOBAL {
-		let gintsts = &mut global.otg_hs_gintsts;
-		let gintmsk = &mut global.otg_hs_gintmsk;
-		// Clear Gintsts to avoid interrupts before init
-		gintsts.update(|_| return);
-
-		for i in 74..78 {
-			::stm32f7::interrupts::enable_interrupt(i, 1, Some(isr), nvic);
+#[cfg(not(feature = "usbip"))]
+use core::cell::RefCell;
+#[cfg(not(feature = "usbip"))]
+use cortex_m::interrupt::Mutex;
+use super::hw::{Hardware, Status};
+use super::error::UsbError;
+use super::control::{Control, Reply, Setup, Stage};
+use super::request::{self, Device, DeviceState, Kind, Recipient};
+use super::descriptor;
+use super::endpoint::{self, Endpoint};
+use super::driver::Mode;
+use super::fifo;
+use super::pool::{self, PACKET_LEN};
+use super::cdc::{self, Acm};
+use super::hid::{self, Hid};
+#[cfg(not(feature = "usbip"))]
+use super::hw::Stm32f7;
+#[cfg(not(feature = "usbip"))]
+use super::driver::Driver;
+
+// room for the 3 back-to-back SETUPs STUPCNT allows
+const SETUP_BUF_WORDS : usize = 3 * 2;
+// reads of GINTSTS to wait for the global OUT NAK, the receive status entries ahead of
+// it are handled meanwhile
+const OUT_NAK_RETRIES : u32 = 100_000;
+
+// The driver, shared between the interrupt and the application's `Usb` handle
+#[cfg(not(feature = "usbip"))]
+pub static USB: Mutex<RefCell<Option<Driver<Stm32f7>>>> = Mutex::new(RefCell::new(None));
+
+// Driver state the interrupt handlers work on
+pub struct State {
+	control: Control,
+	device: Device,
+	acm: Acm,
+	hid: [Option<Hid>; hid::MAX_FUNCTIONS],
+	last_error: Option<UsbError>,
+	error_count: u32,
+	dma: bool,
+	// the FIFO layout of the last bus reset has room for the configuration
+	fifos_planned: bool,
+	// DMA mode: SETUP packets of endpoint 0
+	setup_buf: [u32; SETUP_BUF_WORDS],
+	// receive pool buffer each OUT endpoint receives into
+	rx_slot: [Option<u8>; 8],
+	// DMA mode: packet in flight on IN endpoint 1 to 7
+	in_buf: [[u32; PACKET_LEN / 4]; 7],
+}
+
+impl State {
+	pub fn new() -> State {
+		State {
+			control: Control::new(),
+			device: Device::new(),
+			acm: Acm::new(),
+			hid: hid::functions(descriptor::TREE),
+			last_error: None,
+			error_count: 0,
+			dma: false,
+			fifos_planned: false,
+			setup_buf: [0; SETUP_BUF_WORDS],
+			rx_slot: [None; 8],
+			in_buf: [[0; PACKET_LEN / 4]; 7],
 		}
+	}
+
+	pub fn device(&self) -> &Device {
+		&self.device
+	}
 
-		//interrupts
-		gintmsk.update(|r| r.set_otgint(true));
-		gintmsk.update(|r| r.set_mmism(true));
+	pub fn acm(&self) -> &Acm {
+		&self.acm
+	}
+
+	pub fn hid(&mut self, interface: u8) -> Option<&mut Hid> {
+		hid::find(&mut self.hid, interface)
+	}
+
+	// Most recent error the driver recovered from, cleared by reading it.
+	pub fn take_error(&mut self) -> Option<UsbError> {
+		self.last_error.take()
+	}
 
-		// device interrupts
-		gintmsk.update(|r| r.set_esuspm(true));
-		gintmsk.update(|r| r.set_usbsuspm(true));
-		gintmsk.update(|r| r.set_usbrst(true));
-		gintmsk.update(|r| r.set_enumdnem(true));
-		//gintmsk.update(|r| r.set_sofm(true));
-		gintmsk.update(|r| r.set_oepint(true));
+	pub fn error_count(&self) -> u32 {
+		self.error_count
 	}
+
+	// Gives the receive pool buffers of the OUT endpoints back.
+	fn release_rx(&mut self) {
+		for slot in self.rx_slot.iter_mut() {
+			if let Some(index) = slot.take() {
+				pool::RX.release(index);
+			}
+		}
+	}
+}
+
+// `start` replaces the state and usbip drops a driver with every connection, the pool
+// must not lose the buffers either time.
+impl Drop for State {
+	fn drop(&mut self) {
+		self.release_rx();
+	}
+}
+
+#[cfg(not(feature = "usbip"))]
+pub fn init(driver: Driver<Stm32f7>, nvic: &mut Nvic) {
+	::cortex_m::interrupt::free(|cs| {
+		let mut usb = USB.borrow(cs).borrow_mut();
+		*usb = Some(driver);
+		if let Some(ref mut driver) = *usb {
+			driver.start();
+		}
+	});
+
+	for i in 74..78 {
+		::stm32f7::interrupts::enable_interrupt(i, 1, Some(isr), nvic);
+	}
+}
+
+// Resets the driver state and unmasks the core interrupts the driver handles.
+pub fn start(hw: &mut Hardware, state: &mut State, mode: Mode) {
+	*state = State::new();
+	state.device.set_serial_number(hw.unique_id());
+	state.dma = mode == Mode::Dma;
+	let dma = state.dma;
+	hw.global().otg_hs_gahbcfg.update(|r| {
+		r.set_dmaen(dma);
+		r.set_hbstlen(0x3); // INCR4 bursts
+	});
+
+	// Clear Gintsts to avoid interrupts before init
+	let gintsts = hw.global().otg_hs_gintsts.read().bits;
+	hw.acknowledge(Status::Gintsts, gintsts);
+	let gintmsk = &mut hw.global().otg_hs_gintmsk;
+
+	//interrupts
+	gintmsk.update(|r| r.set_otgint(true));
+	gintmsk.update(|r| r.set_mmism(true));
+
+	// device interrupts
+	gintmsk.update(|r| r.set_esuspm(true));
+	gintmsk.update(|r| r.set_usbsuspm(true));
+	gintmsk.update(|r| r.set_usbrst(true));
+	gintmsk.update(|r| r.set_enumdnem(true));
+	gintmsk.update(|r| r.set_oepint(true));
+	gintmsk.update(|r| r.set_iepint(true));
 }
 
+#[cfg(not(feature = "usbip"))]
 unsafe fn isr(irq: u8) {
 	assert!(74 <= irq && irq <= 77);
-	if let Some(ref mut global) = GLOBAL {
-	if let Some(ref mut device) = DEVICE {
-		let mut gintsts_s = global.otg_hs_gintsts.read();
-		let gintsts = gintsts_s.bits;
-		let gintmsk = global.otg_hs_gintmsk.read().bits;
-		GINTSTS_TRIGGERED |= gintsts;
-	
-		if let Some(ref mut lcd)  = LCD {
-			render::interrupt_debug(gintsts, GINTSTS_TRIGGERED, 
-				&mut COUNT, &mut LAST_ROW, &mut LAST_MASK, lcd);
+	::cortex_m::interrupt::free(|cs| {
+		if let Some(ref mut driver) = *USB.borrow(cs).borrow_mut() {
+			driver.poll();
 		}
+	});
+}
 
-		if gintsts & !gintmsk & (1<<19) != 0{
-			let a = 434;
-		}
+// Runs the handlers of all pending and unmasked core interrupts.
+pub fn dispatch(hw: &mut Hardware, state: &mut State) {
+	let gintsts = hw.global().otg_hs_gintsts.read().bits;
+	let gintmsk = hw.global().otg_hs_gintmsk.read().bits;
 
-		for (i, f) in USB_ISRS.iter().enumerate().filter(|&(i, o)| o.is_some() && (gintmsk & gintsts & (1<<i) != 0)) { 
-			IRQ_HIST[IRQ_IDX] = ((COUNT-1) as u8, i as u8);
-			IRQ_IDX += 1;
-			f.unwrap()(global, device); 
-		} 
-		gintsts_s.bits &= gintmsk & 0b11110000011100001111110000001010; //rw mask
-		global.otg_hs_gintsts.write(gintsts_s);
+	for (_, f) in USB_ISRS.iter().enumerate().filter(|&(i, o)| o.is_some() && (gintmsk & gintsts & (1<<i) != 0)) { 
+		if let Err(e) = f.unwrap()(hw, state) {
+			recover(hw, state, e);
+		}
+	} 
+	hw.acknowledge(Status::Gintsts, gintsts & gintmsk & 0b11110000011100001111110000001010); //rw mask
+}
 
+// Brings the core back into a usable state after a handler failed and records the error.
+fn recover(hw: &mut Hardware, state: &mut State, e: UsbError) {
+	match e {
+		UsbError::UnexpectedPacket { .. } => {
+			state.control.stall();
+			stall0(hw);
+		},
+		// endpoint 0 is not involved, the data endpoint NAKs until it has a buffer again
+		UsbError::PacketDropped { ep, .. } => {
+			if endpoint::is_data(ep) {
+				doepctl!(hw.device(), ep, |r| r.update(|r| r.set_snak(true)));
+			}
+		},
+		UsbError::ModeMismatch => {
+			hw.soft_reset();
+			state.control.reset();
+		},
+		UsbError::UnsupportedSpeed(_) | UsbError::PinInUse | UsbError::FifoRamExhausted { .. } |
+		UsbError::DisableTimeout { .. } => (),
 	}
-	}
+	state.last_error = Some(e);
+	state.error_count = state.error_count.wrapping_add(1);
 }
 
-type UsbIsr = Option<fn(global: &mut OtgHsGlobal, device: &mut OtgHsDevice)>;
+type UsbIsr = Option<fn(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError>>;
 const USB_ISRS : [UsbIsr; 32] = [
 /*00*/	None,
 /*01*/	Some(mmism),
 /*02*/	Some(gotgint),
-/*03*/	None,
+/*03*/	Some(sof),
 /*04*/	Some(rxflvl),
 /*05*/	None,
 /*06*/	None,
@@ -131,23 +233,19 @@ const USB_ISRS : [UsbIsr; 32] = [
 ];
 // Interrupt Handlers: --------------------------------------------------------
 #[allow(unused_variables)]
-fn mmism(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
-	unsafe { asm!("bkpt 0xAB"); }
+fn mmism(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	Err(UsbError::ModeMismatch)
 }
 
 #[allow(unused_variables)]
-fn usbrst(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
+fn usbrst(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
 	//Endpoint initialization on USB reset
 
 	//1.Set the NAK bit for all OUT endpoints
 		//SNAK = 1 in OTG_DOEPCTLx (for all OUT endpoints)
-	device.otg_hs_doepctl1.update(|r| r.set_snak(true));
-	device.otg_hs_doepctl2.update(|r| r.set_snak(true));
-	device.otg_hs_doepctl3.update(|r| r.set_snak(true));
-	device.otg_hs_doepctl4.update(|r| r.set_snak(true));
-	device.otg_hs_doepctl5.update(|r| r.set_snak(true));
-	device.otg_hs_doepctl6.update(|r| r.set_snak(true));
-	device.otg_hs_doepctl7.update(|r| r.set_snak(true));
+	// the reset also ends the configuration, so the data endpoints are deactivated as well
+	let deactivated = deactivate_all(hw, state);
+	state.release_rx();
 	//2. Unmask the following interrupt bits
 		//INEP0 = 1 in OTG_DAINTMSK (control 0 IN endpoint)
 		//OUTEP0 = 1 in OTG_DAINTMSK (control 0 OUT endpoint)
@@ -155,17 +253,17 @@ fn usbrst(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
 		//XFRCM = 1 in OTG_DOEPMSK
 		//XFRCM = 1 in OTG_DIEPMSK
 		//TOM = 1 in OTG_DIEPMSK
-	device.otg_hs_daintmsk.update(|r| {
+	hw.device().otg_hs_daintmsk.update(|r| {
 		let iepm = r.iepm();
 		r.set_iepm(iepm | 0x1);
 		let oepm = r.oepm();
 		r.set_oepm(oepm | 0x1);
 	});
-	device.otg_hs_doepmsk.update(|r| {
+	hw.device().otg_hs_doepmsk.update(|r| {
 		r.set_stupm(true);
 		r.set_xfrcm(true);
 	});
-	device.otg_hs_diepmsk.update(|r| {
+	hw.device().otg_hs_diepmsk.update(|r| {
 		r.set_xfrcm(true);
 		r.set_tom(true);
 	});
@@ -174,274 +272,1061 @@ fn usbrst(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
 		setup data. If thresholding is not enabled, at a minimum, this must be equal to 1 
 		max packet size of control endpoint 0 + 2 Words (for the status of the control OUT 
 		data packet) + 10 Words (for setup packets). */
-	global.otg_hs_grxfsiz.update(|r| r.set_rxfd(0x200)); //2KB
 		/*Program the OTG_DIEPTXF0 register (depending on the FIFO number chosen) to 
 		be able to transmit control IN data. At a minimum, this must be equal to 1 max 
 		packet size of control endpoint 0. */
-	//global.otg_hs_dieptxf0.update(|r| {
-	global.otg_hs_hnptxfsiz_host.update(|r| {
-		//r.set_tx0fd(0x200); //default value (2KB)
-		r.set_nptxfd(0x200); //default value (2KB)
-		//r.set_tx0fsa(0x200); //default value 
-		r.set_nptxfsa(0x200); //default value 
-	});
+	// the FIFOs of all endpoints of the configuration are laid out up front, endpoint 0
+	// alone always fits
+	let mps0 = descriptor::DEVICE_DESCRIPTOR.mps as u16;
+	let planned = fifo::plan(mps0, descriptor::TREE.iter().filter_map(Endpoint::from_descriptor));
+	state.fifos_planned = planned.is_ok();
+	let layout = match planned {
+		Ok(layout) => layout,
+		Err(_) => fifo::plan(mps0, ::core::iter::empty()).unwrap(),
+	};
+	fifo::program(hw, &layout);
 	/*4. Program the following fields in the endpoint-specific registers for control OUT endpoint 
 			0 to receive a SETUP packet */
 		//STUPCNT = 3 in OTG_DOEPTSIZ0 (to receive up to 3 back-to-back SETUP packets)
-	device.otg_hs_doeptsiz0.update(|r| r.set_stupcnt(3));
+	hw.device().otg_hs_doeptsiz0.update(|r| r.set_stupcnt(3));
+	state.control.reset();
+	state.device.reset();
+	reset_functions(state);
+	update_sof(hw, state);
+	hw.device().otg_hs_dcfg.update(|r| r.set_dad(0));
 	/*5. For USB OTG HS in DMA mode, the OTG_DOEPDMA0 register should have a valid 	memory address 
 		to store any SETUP packets received. */
-	//DMA ONLY
+	if state.dma {
+		let addr = state.setup_buf.as_ptr() as u32;
+		hw.device().otg_hs_doepdma0.update(|r| r.bits = addr);
+	}
 
 	//At this point, all initialization required to receive SETUP packets is done.
+	try!(deactivated);
+	planned.map(|_| ())
 }
 
 #[allow(unused_variables)]
-fn enumdne(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
+fn enumdne(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
 	//Endpoint initialization on enumeration completion
 
 	/*1.On the Enumeration Done interrupt (ENUMDNE in OTG_GINTSTS), read the 
 		OTG_DSTS register to determine the enumeration speed. */
-	let enumspd = device.otg_hs_dsts.read().enumspd();
+	let enumspd = hw.device().otg_hs_dsts.read().enumspd();
 	//assert_eq!(enumspd, 0x3);
-	if enumspd == 0x3 {
-		unsafe {
-			asm!("bkpt 0xAB");
-		}
-	}
 	/*2. Program the MPSIZ field in OTG_DIEPCTL0 to set the maximum packet size. This 
 		step configures control endpoint 0. The maximum packet size for a control endpoint 
 		depends on the enumeration speed. */
-	device.otg_hs_diepctl0.update(|r| r.set_mpsiz(0x200));
+	let mpsiz = match descriptor::DEVICE_DESCRIPTOR.mps {
+		64 => 0,
+		32 => 1,
+		16 => 2,
+		_ => 3,
+	};
+	hw.device().otg_hs_diepctl0.update(|r| r.set_mpsiz(mpsiz));
 	/*3. For USB OTG HS in DMA mode, program the OTG_DOEPCTL0 register to enable 
 		control OUT endpoint 0, to receive a SETUP packet. */
-	//DMA ONLY?
+	if state.dma {
+		arm_setup(hw, state);
+	}
 
 	/*At this point, the device is ready to receive SOF packets and is configured to perform 
 		control transfers on control endpoint 0. */
-	global.otg_hs_gintmsk.update(|r| r.set_rxflvlm(true));
+	// in DMA mode the receive status queue is popped by the core
+	if !state.dma {
+		hw.global().otg_hs_gintmsk.update(|r| r.set_rxflvlm(true));
+	}
+
+	// the full speed fallback works with the same endpoint 0 setup, report it anyway
+	if enumspd != 0x0 {
+		return Err(UsbError::UnsupportedSpeed(enumspd));
+	}
+	Ok(())
 }
 
 #[allow(unused_variables)]
-fn gotgint(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
-	let gotgint_r = &mut global.otg_hs_gotgint;
-	let gotgint_s = gotgint_r.read();
-	gotgint_r.write(gotgint_s);
-}
-
-#[derive(Copy, Clone)]
-struct Packet {
-	ep: u8,
-	data: CtlPacket, 
-}
-#[derive(PartialEq)]
-#[derive(Copy, Clone)]
-enum CtlPacket {
-	Setup {
-		request_type: u8,
-		request: u8,
-		value: u16,
-		index: u16,
-		length: u16,
-	},
-	SetupDone,
-	PLACEHOLDER
-}
-
-impl Packet {
-	fn new(ep: u8, count: usize, status: u8, dpid: u8, frame_no: u8, data: &[u8]) -> Packet {
-		Packet { ep: ep, data: CtlPacket::new(status, count, dpid, data) }
-	}
-}
-
-impl CtlPacket {
-	fn new(status: u8, count: usize, dpid: u8, data: &[u8]) -> CtlPacket {
-		match (status, dpid, count) {
-			(0x6, 0x0, 8) => CtlPacket::Setup {
-				request_type: data[0],
-				request: data[1],
-				value: ((data[3] as u16) << 8u16) | data[2] as u16,
-				index: ((data[5] as u16) << 8u16) | data[4] as u16,
-				length: ((data[7] as u16) << 8u16) | data[6] as u16,
-			},
-			(0x4, 0x0, 0) => {
-				CtlPacket::SetupDone
-			},
-			_ => { unimplemented!(); }
+fn gotgint(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	let gotgint = hw.global().otg_hs_gotgint.read().bits;
+	hw.acknowledge(Status::Gotgint, gotgint);
+	Ok(())
+}
+
+// largest packet on endpoint 0
+const EP0_BUF_LEN : usize = 64;
+
+// GRXSTSP packet status
+const GLOBAL_OUT_NAK : u8 = 0x1;
+const OUT_DATA : u8 = 0x2;
+const OUT_DONE : u8 = 0x3;
+const SETUP_DONE : u8 = 0x4;
+const SETUP_DATA : u8 = 0x6;
+
+// Start of a microframe: repeats the input reports that are due under the idle rate of
+// their HID interface. A report that finds its endpoint busy goes in a later one.
+fn sof(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	let mut buf = [0; hid::REPORT_LEN];
+	for i in 0..hid::MAX_FUNCTIONS {
+		let due = match state.hid[i] {
+			Some(ref mut hid) => hid.frame(&mut buf).map(|len| (hid.interface(), len)),
+			None => None,
+		};
+		if let Some((interface, len)) = due {
+			send_report(hw, state, interface, &buf[..len]);
 		}
 	}
+	Ok(())
 }
 
 #[allow(unused_variables)]
-fn rxflvl(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
-	global.otg_hs_gintmsk.update(|r| r.set_rxflvlm(false));
+fn rxflvl(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	hw.global().otg_hs_gintmsk.update(|r| r.set_rxflvlm(false));
 
-	let grxstsp = global.otg_hs_grxstsp_host.read().bits;
+	let grxstsp = hw.pop_rx_status();
 	let ep = (grxstsp & 0xf) as u8;
 	let count = ((grxstsp & 0x7ff0) >> 4) as usize;
 	let status = ((grxstsp & (0xf << 17)) >> 17) as u8;
 	let dpid = ((grxstsp >> 15) & 0x3) as u8;
-	let frame_no = ((grxstsp >> 21) & 0xf) as u8;
-
-	let mut data = Vec::<u8>::with_capacity(count as usize);
-	let mut adata = [0u8; 64];
-	let ptr = (0x1000usize + 0x4004_0000) as *const u32;
-
-	let mut read_bytes = count;
-	for i in 0..(count+3)/4 {
-		unsafe {
-			let word = ::core::ptr::read_volatile(ptr);
-			for j in 0..::core::cmp::min(4, read_bytes) {
-				data.push((word >> (j*8)) as u8);
-				adata[i*4+j] = data[i*4+j];
+
+	let result = match (ep, status) {
+		(0, SETUP_DATA) if count == 8 => {
+			let mut data = [0u8; 8];
+			read_packet(hw, &mut data, count);
+			state.control.setup_received(&data);
+			Ok(())
+		},
+		(0, OUT_DATA) if count <= EP0_BUF_LEN => {
+			let mut data = [0u8; EP0_BUF_LEN];
+			read_packet(hw, &mut data[..count], count);
+			state.control.out_received(&data[..count]);
+			Ok(())
+		},
+		(n, OUT_DATA) if n != 0 => {
+			match state.rx_slot.get(n as usize).and_then(|slot| *slot) {
+				Some(index) if count <= PACKET_LEN => {
+					let buffer = unsafe { pool::RX.buffer(index) };
+					read_packet(hw, buffer.buf(), count);
+					buffer.set_received(n, count);
+					Ok(())
+				},
+				_ => Err(UsbError::PacketDropped { ep: n, count: count as u16 }),
 			}
-			read_bytes -= 4;
-		}
-	}
-	
-	let packet = Packet::new(ep, count, status, dpid, frame_no, &data);
-	unsafe {
-		PACKET_HIST[PACKET_IDX] = packet; 
-		PACKET_IDX += 1;
+		},
+		(_, SETUP_DONE) | (_, OUT_DONE) | (_, GLOBAL_OUT_NAK) => Ok(()),
+		_ => Err(UsbError::UnexpectedPacket {
+			ep: ep,
+			status: status,
+			dpid: dpid,
+			count: count as u16,
+		}),
+	};
+
+	if result.is_err() {
+		// drop the data so that the next entry can be read
+		read_packet(hw, &mut [], count);
 	}
-	unsafe {
-		if let Some(ref mut list) = RECEIVE {
-			list.push_back(packet);
+	hw.global().otg_hs_gintmsk.update(|r| r.set_rxflvlm(true));
+	result
+}
+
+// Reads a packet of `count` bytes from the RX FIFO into `buf`, whatever does not fit is
+// dropped.
+fn read_packet(hw: &mut Hardware, buf: &mut [u8], count: usize) {
+	for i in 0..(count + 3) / 4 {
+		let word = hw.read_fifo();
+		for j in 0..4 {
+			if let Some(byte) = buf.get_mut(i*4 + j) {
+				*byte = (word >> (j*8)) as u8;
+			}
 		}
 	}
+}
 
-	global.otg_hs_gintmsk.update(|r| r.set_rxflvlm(true));
+// Starts an IN packet of `len` bytes on endpoint 0. The DMA fetches the data from `dma`,
+// in slave mode it is written once TXFE reports room for it.
+fn send0(hw: &mut Hardware, dma: Option<u32>, len: usize) {
+	if let Some(addr) = dma {
+		hw.device().otg_hs_diepdma0.update(|r| r.bits = addr);
+	}
+	hw.device().otg_hs_dieptsiz0.update(|r| {
+		r.set_pktcnt(1);
+		r.set_xfrsiz(len as u8);
+	});
+	hw.device().otg_hs_diepctl0.update(|r| {
+		r.set_epena(true);
+		r.set_cnak(true);
+	});
+	if len > 0 && dma.is_none() {
+		hw.device().otg_hs_diepempmsk.update(|r| { let a = r.ineptxfem(); r.set_ineptxfem(a | 0x1); });
+	}
 }
 
-fn send(data: &[u32], byte_cnt: usize, device: &mut OtgHsDevice, global: &mut OtgHsGlobal) {
-	assert!(byte_cnt < 64); // < MPS
-	unsafe{
-	//if debug_1 { return; }
-	debug_1 = true;
+fn write_packet(hw: &mut Hardware, ep: u8, data: &[u8]) {
+	for chunk in data.chunks(4) {
+		let mut word = 0u32;
+		for (i, byte) in chunk.iter().enumerate() {
+			word |= (*byte as u32) << (i*8);
+		}
+		hw.write_fifo(ep, word);
 	}
+}
 
-	let word_cnt = data.len();
-	device.otg_hs_dieptsiz0.update(|r| {
-		r.set_pktcnt(1); 
-		r.set_xfrsiz(byte_cnt as u8);
+// Enables OUT endpoint 0 for one packet of the data or status stage, the DMA writes it
+// to `dma`.
+fn receive0(hw: &mut Hardware, dma: Option<u32>, len: usize) {
+	if let Some(addr) = dma {
+		hw.device().otg_hs_doepdma0.update(|r| r.bits = addr);
+	}
+	hw.device().otg_hs_doeptsiz0.update(|r| {
+		r.set_stupcnt(3);
+		r.set_pktcnt(1);
+		r.set_xfrsiz(len as u8);
 	});
-	device.otg_hs_diepctl0.update(|r| {
+	hw.device().otg_hs_doepctl0.update(|r| {
 		r.set_epena(true);
 		r.set_cnak(true);
 	});
-	while device.otg_hs_dtxfsts0.read().ineptfsav() < word_cnt as u16 {}
-	for i in 0..word_cnt {
-			let ptr = (0x1000usize + 0x4004_0000) as *mut u32;
-			unsafe { ::core::ptr::write_volatile(ptr, data[i]); }
+}
+
+// Protocol stall, the core clears it on the next SETUP.
+fn stall0(hw: &mut Hardware) {
+	hw.device().otg_hs_diepctl0.update(|r| r.set_stall(true));
+	hw.device().otg_hs_doepctl0.update(|r| r.set_stall(true));
+}
+
+fn unstall0(hw: &mut Hardware) {
+	hw.device().otg_hs_diepctl0.update(|r| r.set_stall(false));
+	hw.device().otg_hs_doepctl0.update(|r| r.set_stall(false));
+}
+
+// Sets or clears STALL of endpoint `address` (bEndpointAddress) 1 to 7. Clearing resets
+// the data toggle to DATA0 as well.
+fn set_stall(hw: &mut Hardware, address: u8, stall: bool) {
+	let ep = address & 0x7f;
+	if address & 0x80 != 0 {
+		diepctl!(hw.device(), ep, |r| r.update(|r| {
+			r.set_stall(stall);
+			if !stall { r.set_sd0pid_sevnfrm(true); }
+		}));
+	} else {
+		doepctl!(hw.device(), ep, |r| r.update(|r| {
+			r.set_stall(stall);
+			if !stall { r.set_sd0pid_sevnfrm(true); }
+		}));
+	}
+}
+
+// Sets the global OUT NAK and waits until it is in effect, for at most OUT_NAK_RETRIES
+// reads of GINTSTS. In slave mode the core reports it through the receive status queue,
+// the entries ahead of it are handled as usual.
+fn global_out_nak(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	hw.global_out_nak(true);
+	for _ in 0..OUT_NAK_RETRIES {
+		let gintsts = hw.global().otg_hs_gintsts.read();
+		if gintsts.boutnakeff() {
+			return Ok(());
+		}
+		if gintsts.rxflvl() && !state.dma {
+			if let Err(e) = rxflvl(hw, state) {
+				recover(hw, state, e);
+			}
+		}
+	}
+	Err(UsbError::DisableTimeout { ep: 0 })
+}
+
+// Deactivates data endpoint `address` (bEndpointAddress), an enabled OUT endpoint is
+// disabled under the global OUT NAK (RM0385, OUT endpoint disable). False if the core
+// does not have the endpoint, a timeout of the core is recorded as the last error.
+pub fn deactivate(hw: &mut Hardware, state: &mut State, address: u8) -> bool {
+	let nak = address & 0x80 == 0 && endpoint::out_enabled(hw, address & 0x7f);
+	let nak_set = if nak { global_out_nak(hw, state) } else { Ok(()) };
+	let deactivated = endpoint::deactivate(hw, address);
+	if nak {
+		hw.global_out_nak(false);
+	}
+	match nak_set.and(deactivated) {
+		Ok(deactivated) => deactivated,
+		Err(e) => {
+			recover(hw, state, e);
+			true
+		},
+	}
+}
+
+// Deactivates endpoints 1 to 7 in both directions.
+fn deactivate_all(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	let nak = (1..endpoint::NUM_ENDPOINTS).any(|n| endpoint::out_enabled(hw, n));
+	let nak_set = if nak { global_out_nak(hw, state) } else { Ok(()) };
+	let deactivated = endpoint::deactivate_all(hw);
+	if nak {
+		hw.global_out_nak(false);
+	}
+	nak_set.and(deactivated)
+}
+
+// Halts endpoint `address` or takes the halt back. A halt of endpoint 0 is a protocol
+// stall that ends with the next SETUP. False for an endpoint the core does not have.
+pub fn halt(hw: &mut Hardware, state: &mut State, address: u8, halt: bool) -> bool {
+	if address & 0x7f == 0 {
+		if halt {
+			state.control.stall();
+			stall0(hw);
+		} else {
+			unstall0(hw);
+		}
+		return true;
 	}
-	while device.otg_hs_dieptsiz0.read().xfrsiz() > 0 {}
-	device.otg_hs_diepempmsk.update(|r| { let a = r.ineptxfem(); r.set_ineptxfem(a | 0x1); });
-	global.otg_hs_gintmsk.update(|r| r.set_iepint(true));
+	if !endpoint::is_data(address) {
+		return false;
+	}
+	state.device.set_halted(address, halt);
+	set_stall(hw, address, halt);
+	true
+}
+
+// DMA mode: enables OUT endpoint 0 for SETUP packets, they go to `state.setup_buf`.
+fn arm_setup(hw: &mut Hardware, state: &mut State) {
+	let addr = state.setup_buf.as_ptr() as u32;
+	hw.device().otg_hs_doepdma0.update(|r| r.bits = addr);
+	hw.device().otg_hs_doeptsiz0.update(|r| {
+		r.set_stupcnt(3);
+		r.set_pktcnt(1);
+		r.set_xfrsiz((SETUP_BUF_WORDS * 4) as u8);
+	});
+	hw.device().otg_hs_doepctl0.update(|r| r.set_epena(true));
+}
+
+// DMA mode: the core wrote the last SETUP right below where DOEPDMA0 points now, into
+// `setup_buf` or, if it cut an OUT data stage short, into the buffer of the data stage.
+// An error if DOEPDMA0 points anywhere else.
+fn read_setup(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	let end = hw.device().otg_hs_doepdma0.read().bits;
+	let mut data = [0u8; 8];
+	{
+		let setup_buf = unsafe {
+			::core::slice::from_raw_parts(state.setup_buf.as_ptr() as *const u8, SETUP_BUF_WORDS * 4)
+		};
+		let buffers = [setup_buf, &*state.control.buf()];
+		// DMA addresses are 32 bits wide, like the pointers the driver programs
+		let written = buffers.iter().filter_map(|buf| {
+			let offset = end.wrapping_sub(buf.as_ptr() as u32) as usize;
+			if 8 <= offset && offset <= buf.len() {
+				Some(&buf[offset - 8..offset])
+			} else {
+				None
+			}
+		}).next();
+		match written {
+			Some(bytes) => for (byte, written) in data.iter_mut().zip(bytes.iter()) {
+				*byte = unsafe { ::core::ptr::read_volatile(written) };
+			},
+			None => return Err(UsbError::UnexpectedPacket { ep: 0, status: SETUP_DATA, dpid: 0, count: 8 }),
+		}
+	}
+	state.control.setup_received(&data);
+	Ok(())
+}
+
+// Programs endpoint 0 for the stage the control transfer just entered.
+fn enter(hw: &mut Hardware, state: &mut State, stage: Stage) {
+	let dma = state.dma;
+	match stage {
+		Stage::DataIn => {
+			let packet = state.control.in_packet();
+			send0(hw, if dma { Some(packet.as_ptr() as u32) } else { None }, packet.len());
+		},
+		Stage::DataOut => {
+			let len = state.control.out_packet_len();
+			let addr = state.control.out_buf().as_ptr() as u32;
+			receive0(hw, if dma { Some(addr) } else { None }, len);
+		},
+		Stage::StatusIn => send0(hw, if dma { Some(0) } else { None }, 0),
+		// with the DMA, endpoint 0 has been enabled for SETUPs since the data stage and
+		// takes the zero length packet there as well
+		Stage::StatusOut if dma && hw.device().otg_hs_doepctl0.read().epena() => {
+			hw.device().otg_hs_doepctl0.update(|r| r.set_cnak(true));
+		},
+		Stage::StatusOut => {
+			let addr = state.setup_buf.as_ptr() as u32;
+			receive0(hw, if dma { Some(addr) } else { None }, 0);
+		},
+		Stage::Stall => stall0(hw),
+		Stage::Idle | Stage::Setup | Stage::DataOutDone => (),
+	}
+	// without the DMA the core takes SETUPs any time, with it endpoint 0 needs a buffer.
+	// It is only reprogrammed once the core disabled it after a SETUP or a transfer.
+	let receiving = stage == Stage::DataOut || stage == Stage::StatusOut;
+	if dma && !receiving && !hw.device().otg_hs_doepctl0.read().epena() {
+		arm_setup(hw, state);
+	}
+}
+
+fn setup_done(hw: &mut Hardware, state: &mut State) {
+	hw.device().otg_hs_doeptsiz0.update(|r| r.set_stupcnt(3));
+	match state.control.stage() {
+		Stage::Idle | Stage::Stall => (),
+		// a new SETUP aborts the transfer in progress
+		_ => {
+			hw.device().otg_hs_diepempmsk.update(|r| { let a = r.ineptxfem(); r.set_ineptxfem(a & !0x1); });
+			hw.flush_tx(0);
+		},
+	}
+	if let Some(setup) = state.control.setup_done() {
+		let reply = handle_request(state, &setup);
+		if reply == Reply::Ack && setup.kind() == Kind::Standard {
+			match (setup.request, setup.recipient(), setup.value) {
+				(request::SET_ADDRESS, _, _) => {
+					// the core still answers the status stage at the old address, so the
+					// new one has to be programmed before it is sent
+					let address = state.device.address();
+					hw.device().otg_hs_dcfg.update(|r| r.set_dad(address));
+				},
+				(request::SET_CONFIGURATION, _, _) => {
+					reset_functions(state);
+					if let Err(e) = deactivate_all(hw, state) {
+						recover(hw, state, e);
+					}
+					if state.device.configuration() != 0 {
+						endpoint::configure(hw, descriptor::TREE);
+						rearm(hw, state);
+					}
+				},
+				(request::SET_FEATURE, Recipient::Endpoint, request::ENDPOINT_HALT) |
+				(request::CLEAR_FEATURE, Recipient::Endpoint, request::ENDPOINT_HALT) if endpoint::is_data(setup.index as u8) => {
+					set_stall(hw, setup.index as u8, setup.request == request::SET_FEATURE);
+				},
+				_ => (),
+			}
+		}
+		update_sof(hw, state);
+		let stage = state.control.reply(reply);
+		enter(hw, state, stage);
+	}
+}
+
+// Class requests to an interface go to the function it belongs to, everything else to
+// the device.
+fn handle_request(state: &mut State, setup: &Setup) -> Reply {
+	let configured = state.device.state() == DeviceState::Configured;
+	match (setup.kind(), setup.recipient()) {
+		// without FIFOs for its endpoints the configuration cannot be used
+		(Kind::Standard, _) if setup.request == request::SET_CONFIGURATION && setup.value != 0 && !state.fifos_planned => {
+			Reply::Stall
+		},
+		(Kind::Class, Recipient::Interface) if configured && setup.index == cdc::COMM_INTERFACE as u16 => {
+			state.acm.request(setup, state.control.buf())
+		},
+		(Kind::Class, Recipient::Interface) if configured => {
+			match hid::find(&mut state.hid, setup.index as u8) {
+				Some(hid) => hid.request(setup, state.control.buf()),
+				None => Reply::Stall,
+			}
+		},
+		_ => state.device.request(setup, state.control.buf()),
+	}
+}
+
+// The functions go back to their defaults on a bus reset and on SET_CONFIGURATION.
+fn reset_functions(state: &mut State) {
+	state.acm.reset();
+	for hid in state.hid.iter_mut().filter_map(|f| f.as_mut()) {
+		hid.reset();
+	}
+}
+
+// The SOF interrupt is only unmasked while a HID interface has an idle rate.
+fn update_sof(hw: &mut Hardware, state: &State) {
+	let idle = state.hid.iter().filter_map(|f| f.as_ref()).any(|hid| hid.idle_ms() != 0);
+	hw.global().otg_hs_gintmsk.update(|r| r.set_sofm(idle));
 }
 
-static mut debug_1 : bool = false;
+// Hands the data of the OUT data stage to the request handler.
+fn data_out_done(hw: &mut Hardware, state: &mut State) {
+	if let Some(setup) = state.control.setup() {
+		let reply = match (setup.kind(), setup.recipient()) {
+			(Kind::Class, Recipient::Interface) if setup.index == cdc::COMM_INTERFACE as u16 => {
+				state.acm.data_out(&setup, state.control.data())
+			},
+			(Kind::Class, Recipient::Interface) => {
+				match hid::find(&mut state.hid, setup.index as u8) {
+					Some(hid) => hid.data_out(&setup, state.control.data()),
+					None => Reply::Stall,
+				}
+			},
+			_ => state.device.data_out(&setup, state.control.data()),
+		};
+		let stage = state.control.reply(reply);
+		enter(hw, state, stage);
+	}
+}
 
 #[allow(unused_variables)]
-fn iepint(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
-	let iepint = device.otg_hs_daint.read().iepint();
+fn iepint(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	let iepint = hw.device().otg_hs_daint.read().iepint();
 	if iepint & 0x1 == 1 {
-		let int0 = device.otg_hs_diepint0.read();
-		if int0.txfe() {
-			device.otg_hs_diepempmsk.update(|r| { let a = r.ineptxfem(); r.set_ineptxfem(a & !(0x1)); });
+		let int0 = hw.device().otg_hs_diepint0.read();
+		hw.acknowledge(Status::Diepint(0), int0.bits);
+		// TXFE is level triggered, it only counts while send0 waits for the FIFO
+		let empmsk = hw.device().otg_hs_diepempmsk.read().ineptxfem();
+		if int0.txfe() && empmsk & 0x1 != 0 {
+			let packet = state.control.in_packet();
+			if hw.device().otg_hs_dtxfsts0.read().ineptfsav() as usize >= (packet.len() + 3) / 4 {
+				hw.device().otg_hs_diepempmsk.update(|r| r.set_ineptxfem(empmsk & !0x1));
+				write_packet(hw, 0, packet);
+			}
 		}
 		if int0.xfrc() {
-			unsafe { debug_1 = false; }
+			let stage = state.control.in_done();
+			enter(hw, state, stage);
+		}
+	}
+	// the data endpoints are idle again once EPENA clears, nothing to do but acknowledge
+	for n in 1..endpoint::NUM_ENDPOINTS {
+		if iepint & (1 << n) != 0 {
+			let bits = diepint!(hw.device(), n, |r| r.read().bits).unwrap_or(0);
+			hw.acknowledge(Status::Diepint(n), bits);
 		}
-		device.otg_hs_diepint0.write(int0);
 	}
+	Ok(())
 }
 
-#[allow(unused_variables)]
-fn oepint(global: &mut OtgHsGlobal, device: &mut OtgHsDevice) {
-	let oepint = device.otg_hs_daint.read().oepint();
-
-	unsafe {
-		if PACKET_IDX > 1 {
-			let last = PACKET_HIST[PACKET_IDX-1];
-			let blast = PACKET_HIST[PACKET_IDX-2];
-			if let CtlPacket::Setup { request, .. } = blast.data {
-				if let CtlPacket::SetupDone {} = last.data {
-					if request == 5 {
-						let a = 5;
-					}
-				}
+// Starts an IN packet on endpoint `address` 1 to 7 with as much of `data` as fits in
+// one packet and returns how much that is. Returns 0 while the previous packet is still
+// being sent, the endpoint is inactive or halted or the core does not have it.
+pub fn send(hw: &mut Hardware, state: &mut State, address: u8, data: &[u8]) -> usize {
+	if data.is_empty() {
+		return 0;
+	}
+	start_in(hw, state, address, data).unwrap_or(0)
+}
+
+// Sends a zero length packet on endpoint `address` 1 to 7, it ends a bulk transfer
+// whose last packet was full. False when `send` would return 0.
+pub fn send_zlp(hw: &mut Hardware, state: &mut State, address: u8) -> bool {
+	start_in(hw, state, address, &[]).is_some()
+}
+
+// `send` for `data` of any length, None where `send` returns 0.
+fn start_in(hw: &mut Hardware, state: &mut State, address: u8, data: &[u8]) -> Option<usize> {
+	let n = address & 0x7f;
+	let ctl = match diepctl!(hw.device(), n, |r| r.read()) {
+		Some(ctl) => ctl,
+		None => return None,
+	};
+	if !ctl.usbaep() || ctl.epena() || ctl.stall() {
+		return None;
+	}
+	let len = ::core::cmp::min(data.len(), ::core::cmp::min(ctl.mpsiz() as usize, PACKET_LEN));
+	if state.dma {
+		let buf = &mut state.in_buf[n as usize - 1];
+		for (i, byte) in data[..len].iter().enumerate() {
+			if i % 4 == 0 {
+				buf[i / 4] = 0;
 			}
+			buf[i / 4] |= (*byte as u32) << ((i % 4) * 8);
+		}
+		let addr = buf.as_ptr() as u32;
+		diepdma!(hw.device(), n, |r| r.update(|r| r.bits = addr));
+	}
+	dieptsiz!(hw.device(), n, |r| r.update(|r| {
+		r.set_pktcnt(1);
+		r.set_xfrsiz(len as u32);
+	}));
+	diepctl!(hw.device(), n, |r| r.update(|r| {
+		r.set_epena(true);
+		r.set_cnak(true);
+	}));
+	// the TX FIFO holds two packets and the last one is gone, so there is room
+	if !state.dma {
+		write_packet(hw, n, &data[..len]);
+	}
+	Some(len)
+}
+
+// Sends input report `report` on the IN endpoint of HID interface `interface`, false
+// while the previous report is still on its way or if it does not fit into one packet.
+// GET_REPORT answers with the last report sent.
+pub fn send_report(hw: &mut Hardware, state: &mut State, interface: u8, report: &[u8]) -> bool {
+	let in_ep = match hid::find(&mut state.hid, interface) {
+		Some(hid) if hid.in_ep() != 0 => hid.in_ep(),
+		_ => return false,
+	};
+	// a report goes out in one packet
+	let mps = diepctl!(hw.device(), in_ep & 0x7f, |r| r.read().mpsiz()).unwrap_or(0) as usize;
+	if report.len() > ::core::cmp::min(mps, PACKET_LEN) || send(hw, state, in_ep, report) == 0 {
+		return false;
+	}
+	if let Some(hid) = hid::find(&mut state.hid, interface) {
+		hid.input_sent(report);
+	}
+	true
+}
+
+// Enables OUT endpoint `n` for one packet into a buffer of the receive pool. Without a
+// free buffer the endpoint keeps NAKing until `rearm` finds one, packets are never
+// dropped.
+fn arm_out(hw: &mut Hardware, state: &mut State, n: u8) {
+	let mps = match doepctl!(hw.device(), n, |r| r.read().mpsiz()) {
+		Some(mps) if mps as usize <= PACKET_LEN => mps,
+		_ => return,
+	};
+	let index = match state.rx_slot[n as usize].or_else(|| pool::RX.alloc()) {
+		Some(index) => index,
+		None => return,
+	};
+	state.rx_slot[n as usize] = Some(index);
+	if state.dma {
+		let addr = unsafe { pool::RX.buffer(index) }.buf().as_ptr() as u32;
+		doepdma!(hw.device(), n, |r| r.update(|r| r.bits = addr));
+	}
+	doeptsiz!(hw.device(), n, |r| r.update(|r| {
+		r.set_pktcnt(1);
+		r.set_xfrsiz(mps as u32);
+	}));
+	doepctl!(hw.device(), n, |r| r.update(|r| {
+		r.set_epena(true);
+		r.set_cnak(true);
+	}));
+}
+
+// Enables every active OUT endpoint that is not receiving yet.
+pub fn rearm(hw: &mut Hardware, state: &mut State) {
+	for n in 1..endpoint::NUM_ENDPOINTS {
+		let idle = doepctl!(hw.device(), n, |r| { let ctl = r.read(); ctl.usbaep() && !ctl.epena() });
+		if idle == Some(true) {
+			arm_out(hw, state, n);
+		}
+	}
+}
+
+// Transfer complete on OUT endpoint `n`: hands the packet to the application, or to the
+// HID function it is an output report for, and receives the next one.
+fn out_done(hw: &mut Hardware, state: &mut State, n: u8) {
+	let int = match doepint!(hw.device(), n, |r| r.read()) {
+		Some(int) => int,
+		None => return,
+	};
+	hw.acknowledge(Status::Doepint(n), int.bits);
+	if !int.xfrc() {
+		return;
+	}
+	if let Some(index) = state.rx_slot[n as usize].take() {
+		let buffer = unsafe { pool::RX.buffer(index) };
+		if state.dma {
+			let mps = doepctl!(hw.device(), n, |r| r.read().mpsiz()).unwrap_or(0) as usize;
+			let left = doeptsiz!(hw.device(), n, |r| r.read().xfrsiz()).unwrap_or(0) as usize;
+			buffer.set_received(n, mps.saturating_sub(left));
+		}
+		let out_ep = Some(n);
+		match state.hid.iter_mut().filter_map(|f| f.as_mut()).find(|f| f.out_ep() == out_ep) {
+			Some(hid) => {
+				// the report is copied, the buffer takes the next packet
+				hid.output_received(buffer.data());
+				state.rx_slot[n as usize] = Some(index);
+			},
+			None => pool::RX.submit(index),
 		}
 	}
-	
+	arm_out(hw, state, n);
+}
+
+#[allow(unused_variables)]
+fn oepint(hw: &mut Hardware, state: &mut State) -> Result<(), UsbError> {
+	let oepint = hw.device().otg_hs_daint.read().oepint();
+
 	//endpoint
 	if oepint & 0x1 == 1 {
-		if device.otg_hs_doepint0.read().stup()	{
-			device.otg_hs_doepint0.update(|r| r.set_stup(true));
-			let stupcnt = device.otg_hs_doeptsiz0.read().stupcnt();
-			assert!(stupcnt <= 3);
-			unsafe {
-			if let Some(ref mut list) = RECEIVE {
-				let mut last_packet: Option<Packet> = None;
-				let mut done = false;
-				while let Some(packet) = list.pop_front() {
-					match packet.data { 
-						CtlPacket::Setup {..} 
-							=> last_packet = Some(packet), 
-						CtlPacket::SetupDone {..}
-							=> { done = true; break },
-						_ => panic!()
-					}
-				}
-				if !done && last_packet.is_some() {
-					list.push_front(last_packet.unwrap());
-					return;
-				}
-				
-				if let Some(CtlPacket::Setup {request, value, ..} ) = last_packet.map(|x| x.data) {
-					// GET DESCRIPTOR
-					if request == 6 {
-						let desc_type = (value >> 8) & 0xf;
-						let desc_idx = value & 0xf;
-						// DEVICE Descriptor
-						if desc_type == 1 {
-								let length 		: u8	= 18;
-								let desc_type	: u8 	= desc_type as u8;
-								let bcd_usb		: u16 	= 0x0200;
-								let class		: u8	= 0; //interface specific
-								let subclass	: u8	= 0;
-								let proto		: u8	= 0;
-								let mps			: u8	= 64;
-								let vendor		: u16	= 0x3412;
-								let product		: u16	= 0x7856;
-								let bcd_device	: u16	= 0x5713;
-								let ivendor		: u8	= 0x0;
-								let iproduct	: u8	= 0x0;
-								let iserial		: u8	= 0x0;
-								let numconfig	: u8	= 1; //num configuration descriptors
-
-								#[repr(C)]
-								struct dev_desc(u8, u8, u16, u8, u8, u8, u8, u16, u16, u16, u8, u8, u8, u8, u16);
-								assert_eq!(::core::mem::size_of::<dev_desc>(), 20);
-								let device_descriptor = dev_desc(length, desc_type, bcd_usb, class, subclass,
-									proto, mps, vendor, product, bcd_device, ivendor, iproduct, iserial, 
-									numconfig, 0);
-								let data = ::core::intrinsics::transmute::<dev_desc, [u32; 5]>(device_descriptor);
-								send(&data, 5, device, global);
-
-						}
-					}
-					// SET ADDRESS
-					else if request == 5 {
-						let a = 5;
-					}
-				}
-
-				let a = 5;
-				let x = 19;
-				}
+		let int0 = hw.device().otg_hs_doepint0.read();
+		hw.acknowledge(Status::Doepint(0), int0.bits);
+		if int0.xfrc() {
+			if state.dma && state.control.stage() == Stage::DataOut {
+				let left = hw.device().otg_hs_doeptsiz0.read().xfrsiz() as usize;
+				let count = state.control.out_packet_len().saturating_sub(left);
+				state.control.out_written(count);
+			}
+			let stage = state.control.out_done();
+			if stage == Stage::DataOutDone {
+				data_out_done(hw, state);
+			} else {
+				enter(hw, state, stage);
 			}
-			device.otg_hs_doeptsiz0.update(|r| r.set_stupcnt(3));
 		}
+		if int0.stup() {
+			let read = if state.dma { read_setup(hw, state) } else { Ok(()) };
+			match read {
+				Ok(()) => setup_done(hw, state),
+				// the SETUP is lost, endpoint 0 stalls until the next one
+				Err(e) => {
+					recover(hw, state, e);
+					enter(hw, state, Stage::Stall);
+				},
+			}
+		}
+	}
+	for n in 1..endpoint::NUM_ENDPOINTS {
+		if oepint & (1 << n) != 0 {
+			out_done(hw, state, n);
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::mock::Mock;
+	use super::super::keyboard;
+
+	// GINTSTS
+	const MMIS : u32 = 1 << 1;
+	const RXFLVL : u32 = 1 << 4;
+	const USBRST : u32 = 1 << 12;
+	const ENUMDNE : u32 = 1 << 13;
+	const IEPINT : u32 = 1 << 18;
+	const OEPINT : u32 = 1 << 19;
+	// DIEPINTx, DOEPINTx
+	const XFRC : u32 = 1 << 0;
+	const STUP : u32 = 1 << 3;
+	const TXFE : u32 = 1 << 7;
+
+	// Raises the core interrupts `bits` and runs the driver on them. The core clears
+	// RXFLVL, IEPINT and OEPINT by itself once the cause is gone, the mock needs a hand.
+	fn handle(hw: &mut Mock, state: &mut State, bits: u32) {
+		hw.global.otg_hs_gintsts.update(|r| r.bits |= bits);
+		dispatch(hw, state);
+		hw.global.otg_hs_gintsts.update(|r| r.bits &= !bits);
+		hw.device.otg_hs_daint.update(|r| r.bits = 0);
+	}
+
+	// A core that went through a bus reset and enumerated at high speed.
+	fn enumerated() -> (Mock, State) {
+		let mut hw = Mock::new();
+		let mut state = State::new();
+		start(&mut hw, &mut state, Mode::Slave);
+		handle(&mut hw, &mut state, USBRST);
+		handle(&mut hw, &mut state, ENUMDNE);
+		(hw, state)
+	}
+
+	// The host sends SETUP packet `data`: the core queues it and the setup stage done
+	// entry, popping the latter raises STUP.
+	fn setup(hw: &mut Mock, state: &mut State, data: [u8; 8]) {
+		hw.push_rx(0, SETUP_DATA, 0, &data);
+		hw.push_rx(0, SETUP_DONE, 0, &[]);
+		while !hw.rx_status.is_empty() {
+			handle(hw, state, RXFLVL);
+		}
+		hw.device.otg_hs_doepint0.update(|r| r.bits = STUP);
+		hw.device.otg_hs_daint.update(|r| r.bits = 1 << 16);
+		handle(hw, state, OEPINT);
+	}
+
+	// The same in DMA mode. DOEPDMA0 points into `state`, so it must not move afterwards.
+	fn dma_enumerate(hw: &mut Mock, state: &mut State) {
+		start(hw, state, Mode::Dma);
+		handle(hw, state, USBRST);
+		handle(hw, state, ENUMDNE);
+	}
+
+	// DMA mode: the core writes SETUP packet `data` to the SETUP buffer endpoint 0 is
+	// enabled for, disables the endpoint and raises STUP.
+	fn dma_setup(hw: &mut Mock, state: &mut State, data: [u8; 8]) {
+		let addr = hw.device.otg_hs_doepdma0.read().bits;
+		assert!(hw.device.otg_hs_doepctl0.read().epena());
+		assert_eq!(addr, state.setup_buf.as_ptr() as u32);
+		state.setup_buf = [0; SETUP_BUF_WORDS];
+		for (i, byte) in data.iter().enumerate() {
+			state.setup_buf[i / 4] |= (*byte as u32) << ((i % 4) * 8);
+		}
+		hw.device.otg_hs_doepdma0.update(|r| r.bits = addr + 8);
+		hw.device.otg_hs_doepctl0.update(|r| { r.set_epena(false); r.set_cnak(false); });
+		hw.device.otg_hs_doepint0.update(|r| r.bits = STUP);
+		hw.device.otg_hs_daint.update(|r| r.bits = 1 << 16);
+		handle(hw, state, OEPINT);
+	}
+
+	// DMA mode: the OUT transfer endpoint 0 is enabled for completes with `len` bytes.
+	fn dma_out_complete(hw: &mut Mock, state: &mut State, len: u8) {
+		hw.device.otg_hs_doeptsiz0.update(|r| { let size = r.xfrsiz(); r.set_xfrsiz(size - len); });
+		hw.device.otg_hs_doepctl0.update(|r| { r.set_epena(false); r.set_cnak(false); });
+		hw.device.otg_hs_doepint0.update(|r| r.bits = XFRC);
+		hw.device.otg_hs_daint.update(|r| r.bits = 1 << 16);
+		handle(hw, state, OEPINT);
+	}
+
+	// The TX FIFO of endpoint 0 has room: returns the IN packet the driver writes to it.
+	fn in_packet(hw: &mut Mock, state: &mut State) -> Vec<u8> {
+		hw.tx_fifo[0].clear();
+		hw.device.otg_hs_dtxfsts0.update(|r| r.bits = 0x200);
+		hw.device.otg_hs_diepint0.update(|r| r.bits = TXFE);
+		hw.device.otg_hs_daint.update(|r| r.bits = 1);
+		handle(hw, state, IEPINT);
+		let len = hw.device.otg_hs_dieptsiz0.read().xfrsiz() as usize;
+		let mut packet = hw.tx_bytes(0);
+		packet.truncate(len);
+		packet
+	}
+
+	// The host acknowledged the IN packet.
+	fn in_complete(hw: &mut Mock, state: &mut State) {
+		hw.device.otg_hs_diepint0.update(|r| r.bits = XFRC);
+		hw.device.otg_hs_daint.update(|r| r.bits = 1);
+		handle(hw, state, IEPINT);
+	}
+
+	#[test]
+	fn bus_reset_prepares_endpoint_0() {
+		let (hw, mut state) = enumerated();
+		assert_eq!(hw.device.otg_hs_dcfg.read().dad(), 0);
+		assert_eq!(hw.device.otg_hs_doeptsiz0.read().stupcnt(), 3);
+		assert_eq!(hw.device.otg_hs_daintmsk.read().iepm() & 0x1, 0x1);
+		assert_eq!(hw.device.otg_hs_daintmsk.read().oepm() & 0x1, 0x1);
+		assert!(hw.device.otg_hs_doepmsk.read().stupm());
+		assert!(hw.global.otg_hs_grxfsiz.read().rxfd() > 0);
+		assert_eq!(hw.device.otg_hs_diepctl0.read().mpsiz(), 0);
+		assert!(hw.global.otg_hs_gintmsk.read().rxflvlm());
+		assert_eq!(state.take_error(), None);
+	}
+
+	#[test]
+	fn get_device_descriptor() {
+		let (mut hw, mut state) = enumerated();
+		setup(&mut hw, &mut state, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 64, 0]);
+		assert_eq!(state.control.stage(), Stage::DataIn);
+		assert!(hw.device.otg_hs_diepctl0.read().epena());
+
+		let mut expected = [0u8; 18];
+		assert_eq!(descriptor::get(descriptor::DEVICE, 0, &mut expected), Some(18));
+		assert_eq!(in_packet(&mut hw, &mut state), expected.to_vec());
+
+		in_complete(&mut hw, &mut state);
+		assert_eq!(state.control.stage(), Stage::StatusOut);
+		assert!(hw.device.otg_hs_doepctl0.read().epena());
+	}
+
+	#[test]
+	fn dma_control_read() {
+		let (mut hw, mut state) = (Mock::new(), State::new());
+		dma_enumerate(&mut hw, &mut state);
+		dma_setup(&mut hw, &mut state, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 64, 0]);
+		assert_eq!(state.control.stage(), Stage::DataIn);
+		assert!(hw.device.otg_hs_diepctl0.read().epena());
+		assert_eq!(hw.device.otg_hs_diepdma0.read().bits, state.control.in_packet().as_ptr() as u32);
+		assert_eq!(hw.device.otg_hs_dieptsiz0.read().xfrsiz(), 18);
+		assert!(hw.tx_bytes(0).is_empty());
+		// endpoint 0 takes the next SETUP or the status stage
+		assert!(hw.device.otg_hs_doepctl0.read().epena());
+		assert_eq!(hw.device.otg_hs_doepdma0.read().bits, state.setup_buf.as_ptr() as u32);
+
+		in_complete(&mut hw, &mut state);
+		assert_eq!(state.control.stage(), Stage::StatusOut);
+		// enabled since the data stage, so not reprogrammed
+		assert_eq!(hw.device.otg_hs_doepdma0.read().bits, state.setup_buf.as_ptr() as u32);
+		assert_eq!(hw.device.otg_hs_doeptsiz0.read().xfrsiz() as usize, SETUP_BUF_WORDS * 4);
+		assert!(hw.device.otg_hs_doepctl0.read().cnak());
+
+		dma_out_complete(&mut hw, &mut state, 0);
+		assert_eq!(state.control.stage(), Stage::Idle);
+		dma_setup(&mut hw, &mut state, [0x80, request::GET_STATUS, 0, 0, 0, 0, 2, 0]);
+		assert_eq!(state.control.stage(), Stage::DataIn);
+		assert_eq!(state.take_error(), None);
+	}
+
+	#[test]
+	fn dma_control_write() {
+		let (mut hw, mut state) = (Mock::new(), State::new());
+		dma_enumerate(&mut hw, &mut state);
+		// configured without activating endpoints
+		let requests = [[0x00, request::SET_ADDRESS, 1, 0, 0, 0, 0, 0], [0x00, request::SET_CONFIGURATION, 1, 0, 0, 0, 0, 0]];
+		for setup in requests.iter() {
+			assert_eq!(state.device.request(&Setup::parse(setup), state.control.buf()), Reply::Ack);
+		}
+		dma_setup(&mut hw, &mut state, [0x21, hid::SET_REPORT, 0, hid::OUTPUT, keyboard::INTERFACE, 0, 1, 0]);
+		assert_eq!(state.control.stage(), Stage::DataOut);
+		assert!(hw.device.otg_hs_doepctl0.read().epena());
+		assert!(hw.device.otg_hs_doepctl0.read().cnak());
+		assert_eq!(hw.device.otg_hs_doepdma0.read().bits, state.control.out_buf().as_ptr() as u32);
+		assert_eq!(hw.device.otg_hs_doeptsiz0.read().xfrsiz(), 1);
+
+		state.control.out_buf()[0] = 0x02;
+		dma_out_complete(&mut hw, &mut state, 1);
+		assert_eq!(state.control.stage(), Stage::StatusIn);
+		assert!(hw.device.otg_hs_diepctl0.read().epena());
+		assert_eq!(hw.device.otg_hs_dieptsiz0.read().xfrsiz(), 0);
+		assert_eq!(state.hid(keyboard::INTERFACE).unwrap().take_output(), Some(&[0x02][..]));
+		// back to the SETUP buffer
+		assert_eq!(hw.device.otg_hs_doepdma0.read().bits, state.setup_buf.as_ptr() as u32);
+
+		in_complete(&mut hw, &mut state);
+		assert_eq!(state.control.stage(), Stage::Idle);
+		assert_eq!(state.take_error(), None);
+	}
+
+	#[test]
+	fn dma_setup_outside_the_buffers_stalls() {
+		let (mut hw, mut state) = (Mock::new(), State::new());
+		dma_enumerate(&mut hw, &mut state);
+		hw.device.otg_hs_doepdma0.update(|r| r.bits = 8);
+		hw.device.otg_hs_doepctl0.update(|r| r.set_epena(false));
+		hw.device.otg_hs_doepint0.update(|r| r.bits = STUP);
+		hw.device.otg_hs_daint.update(|r| r.bits = 1 << 16);
+		handle(&mut hw, &mut state, OEPINT);
+		assert_eq!(state.take_error(), Some(UsbError::UnexpectedPacket { ep: 0, status: SETUP_DATA, dpid: 0, count: 8 }));
+		assert!(hw.device.otg_hs_diepctl0.read().stall());
+		// ready for the next SETUP
+		assert!(hw.device.otg_hs_doepctl0.read().epena());
+		assert_eq!(hw.device.otg_hs_doepdma0.read().bits, state.setup_buf.as_ptr() as u32);
+	}
+
+	#[test]
+	fn configuration_descriptor_goes_out_in_packets() {
+		let (mut hw, mut state) = enumerated();
+		setup(&mut hw, &mut state, [0x80, request::GET_DESCRIPTOR, 0, descriptor::CONFIGURATION, 0, 0, 0xff, 0x01]);
+
+		let mut received = Vec::new();
+		while state.control.stage() == Stage::DataIn {
+			let packet = in_packet(&mut hw, &mut state);
+			assert!(packet.len() <= 64);
+			received.extend_from_slice(&packet);
+			in_complete(&mut hw, &mut state);
+		}
+		let mut expected = [0u8; 512];
+		let len = descriptor::configuration(descriptor::TREE, &mut expected).unwrap();
+		assert!(len > 64);
+		assert_eq!(received, expected[..len].to_vec());
+		assert_eq!(state.control.stage(), Stage::StatusOut);
+	}
+
+	#[test]
+	fn set_address_is_programmed_before_the_status_stage() {
+		let (mut hw, mut state) = enumerated();
+		setup(&mut hw, &mut state, [0x00, request::SET_ADDRESS, 0x2a, 0, 0, 0, 0, 0]);
+		assert_eq!(hw.device.otg_hs_dcfg.read().dad(), 0x2a);
+		assert_eq!(state.control.stage(), Stage::StatusIn);
+		assert_eq!(hw.device.otg_hs_dieptsiz0.read().xfrsiz(), 0);
+		assert_eq!(hw.device.otg_hs_dieptsiz0.read().pktcnt(), 1);
+		assert!(hw.device.otg_hs_diepctl0.read().epena());
+	}
+
+	#[test]
+	fn unknown_descriptor_stalls_endpoint_0() {
+		let (mut hw, mut state) = enumerated();
+		setup(&mut hw, &mut state, [0x80, request::GET_DESCRIPTOR, 0, 0x0f, 0, 0, 64, 0]);
+		assert!(hw.device.otg_hs_diepctl0.read().stall());
+		assert!(hw.device.otg_hs_doepctl0.read().stall());
+		assert_eq!(state.take_error(), None);
+	}
+
+	#[test]
+	fn oversized_packet_on_endpoint_0_is_dropped_and_stalls() {
+		let (mut hw, mut state) = enumerated();
+		hw.push_rx(0, OUT_DATA, 0, &[0x55; 100]);
+		handle(&mut hw, &mut state, RXFLVL);
+		assert!(hw.rx_fifo.is_empty());
+		assert!(hw.device.otg_hs_diepctl0.read().stall());
+		assert_eq!(state.take_error(), Some(UsbError::UnexpectedPacket {
+			ep: 0,
+			status: OUT_DATA,
+			dpid: 0,
+			count: 100,
+		}));
+		assert_eq!(state.error_count(), 1);
+	}
+
+	#[test]
+	fn mode_mismatch_resets_the_core() {
+		let (mut hw, mut state) = enumerated();
+		hw.push_rx(0, SETUP_DATA, 0, &[0; 8]);
+		handle(&mut hw, &mut state, MMIS);
+		assert!(hw.rx_status.is_empty());
+		assert_eq!(state.take_error(), Some(UsbError::ModeMismatch));
+	}
+
+	#[test]
+	fn halt_of_an_endpoint_the_core_lacks_stalls_endpoint_0() {
+		let (mut hw, mut state) = enumerated();
+		setup(&mut hw, &mut state, [0x00, request::SET_ADDRESS, 1, 0, 0, 0, 0, 0]);
+		setup(&mut hw, &mut state, [0x02, request::SET_FEATURE, 0, 0, 0x88, 0, 0, 0]);
+		assert!(hw.device.otg_hs_diepctl0.read().stall());
+		assert!(!state.device.is_halted(0x80));
+	}
+
+	#[test]
+	fn halt_of_an_endpoint_the_core_lacks_is_refused() {
+		let (mut hw, mut state) = enumerated();
+		assert!(!halt(&mut hw, &mut state, 0x88, true));
+		assert!(!halt(&mut hw, &mut state, 0x08, false));
+		assert!(!halt(&mut hw, &mut state, 0x91, true));
+		assert!(!hw.device.otg_hs_diepctl0.read().stall());
+		assert!(halt(&mut hw, &mut state, 0x81, true));
+		assert!(state.device.is_halted(0x81));
+	}
+
+	#[test]
+	fn endpoints_the_core_lacks_are_refused() {
+		let (mut hw, mut state) = enumerated();
+		assert_eq!(send(&mut hw, &mut state, 0x88, &[1, 2, 3]), 0);
+		assert_eq!(send(&mut hw, &mut state, 0x80, &[1, 2, 3]), 0);
+		assert!(hw.tx_bytes(0).is_empty());
+		let ep = Endpoint { address: 0x88, ep_type: endpoint::EpType::Interrupt, mps: 8, fifo: 8 };
+		assert!(!endpoint::activate(&mut hw, &ep));
+		assert!(!deactivate(&mut hw, &mut state, 0x88));
+		assert!(!deactivate(&mut hw, &mut state, 0x08));
+		assert!(deactivate(&mut hw, &mut state, 0x81));
+	}
+
+	#[test]
+	fn disable_timeouts_are_recorded() {
+		let (mut hw, mut state) = enumerated();
+		hw.stuck = true;
+		hw.device.otg_hs_doepctl1.update(|r| { r.set_usbaep(true); r.set_epena(true); });
+		assert!(deactivate(&mut hw, &mut state, 0x01));
+		assert_eq!(state.take_error(), Some(UsbError::DisableTimeout { ep: 0 }));
+		assert!(!hw.device.otg_hs_doepctl1.read().usbaep());
+		assert!(!hw.global.otg_hs_gintsts.read().boutnakeff());
+		hw.device.otg_hs_diepctl2.update(|r| { r.set_usbaep(true); r.set_epena(true); });
+		assert!(deactivate(&mut hw, &mut state, 0x82));
+		assert_eq!(state.take_error(), Some(UsbError::DisableTimeout { ep: 0x82 }));
+		assert!(!hw.device.otg_hs_diepctl2.read().usbaep());
+	}
+
+	#[test]
+	fn set_configuration_stalls_without_fifos() {
+		let (mut hw, mut state) = enumerated();
+		state.fifos_planned = false;
+		setup(&mut hw, &mut state, [0x00, request::SET_ADDRESS, 1, 0, 0, 0, 0, 0]);
+		setup(&mut hw, &mut state, [0x00, request::SET_CONFIGURATION, 1, 0, 0, 0, 0, 0]);
+		assert!(hw.device.otg_hs_diepctl0.read().stall());
+		assert_eq!(state.device.configuration(), 0);
+		assert!(!hw.device.otg_hs_doepctl1.read().usbaep());
+	}
+
+	#[test]
+	fn out_packet_without_a_buffer_is_dropped() {
+		let (mut hw, mut state) = enumerated();
+		hw.push_rx(1, OUT_DATA, 0, b"lost");
+		hw.push_rx(0, SETUP_DATA, 0, &[0x80, request::GET_STATUS, 0, 0, 0, 0, 2, 0]);
+		handle(&mut hw, &mut state, RXFLVL);
+		handle(&mut hw, &mut state, RXFLVL);
+		assert_eq!(state.take_error(), Some(UsbError::PacketDropped { ep: 1, count: 4 }));
+		assert!(hw.device.otg_hs_doepctl1.read().snak());
+		assert!(!hw.device.otg_hs_diepctl0.read().stall());
+		assert!(!hw.device.otg_hs_doepctl0.read().stall());
+		// the data is gone, the SETUP behind it was read from the right place
+		assert!(hw.rx_fifo.is_empty());
+		assert_eq!(state.error_count(), 1);
+	}
+
+	#[test]
+	fn report_longer_than_a_packet_is_refused() {
+		let (mut hw, mut state) = enumerated();
+		hw.device.otg_hs_diepctl3.update(|r| {
+			r.set_mpsiz(8);
+			r.set_usbaep(true);
+		});
+		assert!(!send_report(&mut hw, &mut state, keyboard::INTERFACE, &[0; 9]));
+		assert!(hw.tx_bytes(3).is_empty());
+		assert!(send_report(&mut hw, &mut state, keyboard::INTERFACE, &[0, 0, 4, 0, 0, 0, 0, 0]));
+		assert_eq!(hw.tx_bytes(3), vec![0, 0, 4, 0, 0, 0, 0, 0]);
 	}
 }
@@ -0,0 +1,362 @@
+// Boot protocol keyboard (HID 1.11 appendix B.1) that types strings with a US layout.
+// Every character is a press report followed by a release report, so repeated letters
+// come out as separate key strokes. The reports only depend on the string and the Caps
+// Lock LED, `decode` turns them back into characters.
+
+#[cfg(not(feature = "usbip"))]
+use core::fmt;
+use core::str::Chars;
+use super::hid::{self, Report};
+#[cfg(not(feature = "usbip"))]
+use super::Usb;
+#[cfg(not(feature = "usbip"))]
+use super::request::DeviceState;
+
+// interface number and endpoint address in `descriptor::TREE`
+pub const INTERFACE : u8 = 2;
+pub const IN_EP : u8 = 0x83;
+// polling interval, 2^(4-1) microframes = 1 ms at high speed
+pub const INTERVAL : u8 = 4;
+
+pub const REPORT_LEN : usize = 8;
+// `send_within` gives up on a report after this many attempts
+pub const SEND_ATTEMPTS : u32 = 100_000;
+
+// modifier bits, byte 0 of a report
+pub const LEFT_CTRL : u8 = 0x01;
+pub const LEFT_SHIFT : u8 = 0x02;
+pub const LEFT_ALT : u8 = 0x04;
+pub const LEFT_GUI : u8 = 0x08;
+pub const RIGHT_CTRL : u8 = 0x10;
+pub const RIGHT_SHIFT : u8 = 0x20;
+pub const RIGHT_ALT : u8 = 0x40;
+pub const RIGHT_GUI : u8 = 0x80;
+
+// LED bits of the output report
+const NUM_LOCK : u8 = 0x01;
+const CAPS_LOCK : u8 = 0x02;
+const SCROLL_LOCK : u8 = 0x04;
+
+// usage IDs of the keyboard page
+const KEY_A : u8 = 0x04;
+const KEY_Z : u8 = 0x1d;
+
+// keys other than letters: usage ID, character without and with shift
+const KEYS : &'static [(u8, char, char)] = &[
+	(0x1e, '1', '!'),
+	(0x1f, '2', '@'),
+	(0x20, '3', '#'),
+	(0x21, '4', '$'),
+	(0x22, '5', '%'),
+	(0x23, '6', '^'),
+	(0x24, '7', '&'),
+	(0x25, '8', '*'),
+	(0x26, '9', '('),
+	(0x27, '0', ')'),
+	(0x28, '\n', '\n'), // Enter
+	(0x29, '\x1b', '\x1b'), // Escape
+	(0x2a, '\x08', '\x08'), // Backspace
+	(0x2b, '\t', '\t'),
+	(0x2c, ' ', ' '),
+	(0x2d, '-', '_'),
+	(0x2e, '=', '+'),
+	(0x2f, '[', '{'),
+	(0x30, ']', '}'),
+	(0x31, '\\', '|'),
+	(0x33, ';', ':'),
+	(0x34, '\'', '"'),
+	(0x35, '`', '~'),
+	(0x36, ',', '<'),
+	(0x37, '.', '>'),
+	(0x38, '/', '?'),
+];
+
+// The boot keyboard report descriptor: modifier byte, reserved byte and six key codes in,
+// five LEDs out.
+pub fn report(r: &mut Report) {
+	r.usage_page(hid::GENERIC_DESKTOP).usage(0x06) // keyboard
+		.collection(hid::APPLICATION)
+			// modifiers
+			.usage_page(hid::KEYBOARD).usage_minimum(0xe0).usage_maximum(0xe7)
+			.logical_minimum(0).logical_maximum(1)
+			.report_size(1).report_count(8).input(hid::VARIABLE)
+			// reserved
+			.report_size(8).report_count(1).input(hid::CONSTANT)
+			// LEDs
+			.usage_page(hid::LED).usage_minimum(0x01).usage_maximum(0x05)
+			.report_size(1).report_count(5).output(hid::VARIABLE)
+			.report_size(3).report_count(1).output(hid::CONSTANT)
+			// keys
+			.usage_page(hid::KEYBOARD).usage_minimum(0x00).usage_maximum(0xff)
+			.logical_minimum(0).logical_maximum(0xff)
+			.report_size(8).report_count(6).input(0)
+		.end_collection();
+}
+
+// Keyboard LEDs the host set with the output report.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Leds(pub u8);
+
+impl Leds {
+	pub fn num_lock(&self) -> bool {
+		self.0 & NUM_LOCK != 0
+	}
+
+	pub fn caps_lock(&self) -> bool {
+		self.0 & CAPS_LOCK != 0
+	}
+
+	pub fn scroll_lock(&self) -> bool {
+		self.0 & SCROLL_LOCK != 0
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct KeyReport {
+	pub modifiers: u8,
+	pub keys: [u8; 6],
+}
+
+impl KeyReport {
+	// all keys released
+	pub fn empty() -> KeyReport {
+		KeyReport { modifiers: 0, keys: [0; 6] }
+	}
+
+	// Press of the key typing `c`, None if there is none. Caps Lock inverts the shift of
+	// letters.
+	pub fn from_char(c: char, caps_lock: bool) -> Option<KeyReport> {
+		let (code, shift) = match c {
+			'a'...'z' => (KEY_A + (c as u8 - b'a'), caps_lock),
+			'A'...'Z' => (KEY_A + (c as u8 - b'A'), !caps_lock),
+			_ => match KEYS.iter().find(|k| k.1 == c || k.2 == c) {
+				Some(&(code, normal, _)) => (code, c != normal),
+				None => return None,
+			},
+		};
+		let mut report = KeyReport::empty();
+		report.modifiers = if shift { LEFT_SHIFT } else { 0 };
+		report.keys[0] = code;
+		Some(report)
+	}
+
+	// Report of the bytes `data`, whatever is missing counts as released.
+	pub fn parse(data: &[u8]) -> KeyReport {
+		let mut report = KeyReport::empty();
+		report.modifiers = data.first().cloned().unwrap_or(0);
+		for (key, byte) in report.keys.iter_mut().zip(data.iter().skip(2)) {
+			*key = *byte;
+		}
+		report
+	}
+
+	pub fn bytes(&self) -> [u8; REPORT_LEN] {
+		let mut data = [0; REPORT_LEN];
+		data[0] = self.modifiers;
+		data[2..].copy_from_slice(&self.keys);
+		data
+	}
+
+	// Character the first pressed key types, the inverse of `from_char`.
+	pub fn decode(&self, caps_lock: bool) -> Option<char> {
+		let shift = self.modifiers & (LEFT_SHIFT | RIGHT_SHIFT) != 0;
+		match self.keys[0] {
+			0 => None,
+			code @ KEY_A...KEY_Z => {
+				let first = if shift != caps_lock { b'A' } else { b'a' };
+				Some((first + (code - KEY_A)) as char)
+			},
+			code => KEYS.iter().find(|k| k.0 == code).map(|k| if shift { k.2 } else { k.1 }),
+		}
+	}
+}
+
+// Turns a string into key reports. Characters without a key are skipped.
+pub struct Typer<'a> {
+	chars: Chars<'a>,
+	// the last report pressed a key
+	pressed: bool,
+}
+
+impl<'a> Typer<'a> {
+	pub fn new(s: &'a str) -> Typer<'a> {
+		Typer { chars: s.chars(), pressed: false }
+	}
+
+	// The next report, None once the string is typed and all keys are released.
+	pub fn next_report(&mut self, caps_lock: bool) -> Option<KeyReport> {
+		if self.pressed {
+			self.pressed = false;
+			return Some(KeyReport::empty());
+		}
+		while let Some(c) = self.chars.next() {
+			if let Some(report) = KeyReport::from_char(c, caps_lock) {
+				self.pressed = true;
+				return Some(report);
+			}
+		}
+		None
+	}
+}
+
+// Calls `attempt` until it returns Some(true), the report went out. None, the keyboard
+// is gone, stops right away. False if the report did not go out, so a host that stops
+// polling the endpoint does not hang the caller.
+pub fn send_within<F>(mut attempt: F) -> bool where F: FnMut() -> Option<bool> {
+	for _ in 0..SEND_ATTEMPTS {
+		match attempt() {
+			Some(true) => return true,
+			Some(false) => (),
+			None => return false,
+		}
+	}
+	false
+}
+
+// Handle to the keyboard, get it from `Usb::keyboard`.
+#[cfg(not(feature = "usbip"))]
+pub struct Keyboard<'a> {
+	usb: &'a mut Usb,
+}
+
+#[cfg(not(feature = "usbip"))]
+impl<'a> Keyboard<'a> {
+	pub fn new(usb: &'a mut Usb) -> Keyboard<'a> {
+		Keyboard { usb: usb }
+	}
+
+	// The host configured the device, so the keyboard is polled.
+	pub fn is_ready(&mut self) -> bool {
+		self.usb.with(|driver| driver.device().state() == DeviceState::Configured)
+	}
+
+	pub fn leds(&mut self) -> Leds {
+		self.usb.with(|driver| {
+			let output = driver.hid(INTERFACE).map(|hid| hid.output().first().cloned().unwrap_or(0));
+			Leds(output.unwrap_or(0))
+		})
+	}
+
+	// Starts sending `report`, false while the last one is still waiting for the host.
+	pub fn send(&mut self, report: &KeyReport) -> bool {
+		self.usb.with(|driver| driver.send_report(INTERFACE, &report.bytes()))
+	}
+
+	// Types `s` and waits until the last key is released. A report goes out when the host
+	// polls the endpoint, so each one stays for at least one polling interval. False if
+	// the device got deconfigured meanwhile or a report found the endpoint busy for
+	// `SEND_ATTEMPTS` attempts.
+	pub fn type_str(&mut self, s: &str) -> bool {
+		let mut typer = Typer::new(s);
+		loop {
+			let caps_lock = self.leds().caps_lock();
+			let report = match typer.next_report(caps_lock) {
+				Some(report) => report.bytes(),
+				None => return true,
+			};
+			let usb = &mut self.usb;
+			let sent = send_within(|| usb.with(|driver| {
+				if driver.device().state() != DeviceState::Configured {
+					return None;
+				}
+				Some(driver.send_report(INTERFACE, &report))
+			}));
+			if !sent {
+				return false;
+			}
+		}
+	}
+}
+
+#[cfg(not(feature = "usbip"))]
+impl<'a> fmt::Write for Keyboard<'a> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		if self.type_str(s) { Ok(()) } else { Err(fmt::Error) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use collections::string::String;
+
+	// Decodes the reports of typing `s` the way the host would, a character per press.
+	fn typed(s: &str, caps_lock: bool) -> String {
+		let mut typer = Typer::new(s);
+		let mut text = String::new();
+		let mut pressed = false;
+		while let Some(report) = typer.next_report(caps_lock) {
+			let report = KeyReport::parse(&report.bytes());
+			match report.decode(caps_lock) {
+				Some(c) => {
+					assert!(!pressed, "key pressed without a release before");
+					text.push(c);
+					pressed = true;
+				},
+				None => {
+					assert_eq!(report, KeyReport::empty());
+					pressed = false;
+				},
+			}
+		}
+		assert!(!pressed, "last key not released");
+		text
+	}
+
+	#[test]
+	fn typed_text_decodes_to_the_string() {
+		let s = "Hello, World! 0123456789 ~`!@#$%^&*()-_=+[{]}\\|;:'\",<.>/?\t\n";
+		assert_eq!(typed(s, false), s);
+		assert_eq!(typed(s, true), s);
+	}
+
+	#[test]
+	fn repeated_letters_are_separate_strokes() {
+		let mut typer = Typer::new("ll");
+		let press = KeyReport::from_char('l', false).unwrap();
+		assert_eq!(typer.next_report(false), Some(press));
+		assert_eq!(typer.next_report(false), Some(KeyReport::empty()));
+		assert_eq!(typer.next_report(false), Some(press));
+		assert_eq!(typer.next_report(false), Some(KeyReport::empty()));
+		assert_eq!(typer.next_report(false), None);
+	}
+
+	#[test]
+	fn characters_without_a_key_are_skipped() {
+		assert_eq!(typed("a\u{e4}b\u{1}c", false), "abc");
+	}
+
+	#[test]
+	fn caps_lock_inverts_the_shift_of_letters() {
+		assert_eq!(KeyReport::from_char('a', true).unwrap().modifiers, LEFT_SHIFT);
+		assert_eq!(KeyReport::from_char('A', true).unwrap().modifiers, 0);
+		assert_eq!(KeyReport::from_char('1', true).unwrap().modifiers, 0);
+		assert_eq!(KeyReport::from_char('!', true).unwrap().modifiers, LEFT_SHIFT);
+	}
+
+	#[test]
+	fn sending_gives_up() {
+		let mut attempts = 0;
+		assert!(send_within(|| { attempts += 1; Some(attempts == 3) }));
+		assert_eq!(attempts, 3);
+
+		// the host stopped polling
+		attempts = 0;
+		assert!(!send_within(|| { attempts += 1; Some(false) }));
+		assert_eq!(attempts, SEND_ATTEMPTS);
+
+		// deconfigured
+		attempts = 0;
+		assert!(!send_within(|| { attempts += 1; None }));
+		assert_eq!(attempts, 1);
+	}
+
+	#[test]
+	fn short_reports_parse() {
+		assert_eq!(KeyReport::parse(&[]), KeyReport::empty());
+		assert_eq!(KeyReport::parse(&[LEFT_SHIFT]).modifiers, LEFT_SHIFT);
+		let report = KeyReport::parse(&[RIGHT_SHIFT, 0, 0x04]);
+		assert_eq!(report.keys, [0x04, 0, 0, 0, 0, 0]);
+		assert_eq!(report.decode(false), Some('A'));
+	}
+}
@@ -0,0 +1,131 @@
+use board::otg_hs_global::OtgHsGlobal;
+use board::otg_hs_device::OtgHsDevice;
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+use super::hw::{Hardware, Status};
+
+// GINTSTS.BOUTNAKEFF
+const BOUTNAKEFF : u32 = 1 << 7;
+
+// In-memory stand-in for the OTG HS core. The register blocks are plain memory, the
+// receive status queue and the FIFOs are queues the test fills and inspects.
+pub struct Mock {
+	pub global: OtgHsGlobal,
+	pub device: OtgHsDevice,
+	pub rx_status: VecDeque<u32>,
+	pub rx_fifo: VecDeque<u32>,
+	pub tx_fifo: [Vec<u32>; 8],
+	pub unique_id: [u32; 3],
+	// the global OUT NAK never takes effect and endpoints never report EPDISD
+	pub stuck: bool,
+}
+
+impl Mock {
+	pub fn new() -> Mock {
+		Mock {
+			global: unsafe { ::core::mem::zeroed() },
+			device: unsafe { ::core::mem::zeroed() },
+			rx_status: VecDeque::new(),
+			rx_fifo: VecDeque::new(),
+			tx_fifo: [Vec::new(), Vec::new(), Vec::new(), Vec::new(),
+				Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+			unique_id: [0; 3],
+			stuck: false,
+		}
+	}
+
+	// Queue a receive status entry and its data the way the core would.
+	pub fn push_rx(&mut self, ep: u8, status: u8, dpid: u8, data: &[u8]) {
+		let grxstsp = (ep as u32 & 0xf)
+			| ((data.len() as u32 & 0x7ff) << 4)
+			| ((dpid as u32 & 0x3) << 15)
+			| ((status as u32 & 0xf) << 17);
+		self.rx_status.push_back(grxstsp);
+		for chunk in data.chunks(4) {
+			let mut word = 0u32;
+			for (i, byte) in chunk.iter().enumerate() {
+				word |= (*byte as u32) << (i*8);
+			}
+			self.rx_fifo.push_back(word);
+		}
+	}
+
+	// Bytes written to the TX FIFO of `ep`, in little endian word order.
+	pub fn tx_bytes(&self, ep: u8) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		for word in self.tx_fifo[ep as usize].iter() {
+			for i in 0..4 {
+				bytes.push((*word >> (i*8)) as u8);
+			}
+		}
+		bytes
+	}
+}
+
+impl Hardware for Mock {
+	fn global(&mut self) -> &mut OtgHsGlobal {
+		&mut self.global
+	}
+
+	fn device(&mut self) -> &mut OtgHsDevice {
+		&mut self.device
+	}
+
+	fn pop_rx_status(&mut self) -> u32 {
+		self.rx_status.pop_front().unwrap_or(0)
+	}
+
+	fn read_fifo(&mut self) -> u32 {
+		self.rx_fifo.pop_front().unwrap_or(0)
+	}
+
+	fn write_fifo(&mut self, ep: u8, word: u32) {
+		self.tx_fifo[ep as usize].push(word);
+	}
+
+	fn acknowledge(&mut self, reg: Status, bits: u32) {
+		status_reg!(self.global, self.device, reg, |r| {
+			let mut value = r.read();
+			value.bits &= !bits;
+			r.write(value);
+		});
+	}
+
+	fn soft_reset(&mut self) {
+		self.rx_status.clear();
+		self.rx_fifo.clear();
+		for fifo in self.tx_fifo.iter_mut() {
+			fifo.clear();
+		}
+	}
+
+	fn flush_tx(&mut self, fifo: u8) {
+		self.tx_fifo[fifo as usize].clear();
+	}
+
+	// takes effect right away
+	fn global_out_nak(&mut self, nak: bool) {
+		let effective = nak && !self.stuck;
+		self.global.otg_hs_gintsts.update(|r| if effective { r.bits |= BOUTNAKEFF } else { r.bits &= !BOUTNAKEFF });
+	}
+
+	fn disable_in(&mut self, ep: u8) -> bool {
+		diepctl!(self.device, ep, |r| r.update(|r| {
+			r.set_snak(true);
+			r.set_epena(false);
+		}));
+		!self.stuck
+	}
+
+	fn disable_out(&mut self, ep: u8) -> bool {
+		doepctl!(self.device, ep, |r| r.update(|r| {
+			r.set_snak(true);
+			r.set_epena(false);
+		}));
+		!self.stuck
+	}
+
+	fn unique_id(&mut self) -> [u32; 3] {
+		self.unique_id
+	}
+}
@@ -1,10 +1,99 @@
+#[macro_use]
+pub mod hw;
+pub mod error;
+#[cfg(any(test, feature = "usbip"))]
+pub mod mock;
+#[cfg(any(test, feature = "usbip"))]
+pub mod sim;
+pub mod driver;
+pub mod control;
+pub mod request;
+#[macro_use]
+pub mod descriptor;
+pub mod endpoint;
+pub mod fifo;
+pub mod pool;
+pub mod cdc;
+pub mod hid;
+pub mod keyboard;
+pub mod digitizer;
+pub mod serial;
+#[cfg(not(feature = "usbip"))]
 pub mod init;
 //mod interrupt;
 pub mod interrupt; //debug
 
+#[cfg(not(feature = "usbip"))]
+use self::driver::Driver;
+#[cfg(not(feature = "usbip"))]
+use self::hw::Stm32f7;
+
+// Handle to the driver returned by `init::init`. The driver itself lives in
+// `interrupt::USB` so that the interrupt can reach it.
 pub struct Usb {
+	#[cfg(not(feature = "usbip"))]
+	port: serial::Buffers,
 }
 
+#[cfg(not(feature = "usbip"))]
 impl Usb {
-	
+	// Runs `f` on the driver with interrupts held off.
+	pub fn with<F, R>(&mut self, f: F) -> R where F: FnOnce(&mut Driver<Stm32f7>) -> R {
+		::cortex_m::interrupt::free(|cs| {
+			let mut usb = interrupt::USB.borrow(cs).borrow_mut();
+			f(usb.as_mut().expect("usb driver not initialized"))
+		})
+	}
+
+	// Runs `f` on the serial port buffers and the driver with interrupts held off.
+	fn with_port<F, R>(&mut self, f: F) -> R where F: FnOnce(&mut serial::Buffers, &mut Driver<Stm32f7>) -> R {
+		let port = &mut self.port;
+		::cortex_m::interrupt::free(|cs| {
+			let mut usb = interrupt::USB.borrow(cs).borrow_mut();
+			f(port, usb.as_mut().expect("usb driver not initialized"))
+		})
+	}
+
+	// Oldest packet received on a data endpoint. Endpoints that were left NAKing for lack
+	// of buffers are enabled again once received packets have been dropped.
+	pub fn receive(&mut self) -> Option<pool::Rx> {
+		if pool::RX.take_starved() {
+			self.with(|driver| driver.rearm());
+		}
+		pool::RX.receive()
+	}
+
+	// Oldest packet received on data endpoint `ep`, packets of other endpoints are left
+	// for their readers.
+	pub fn receive_from(&mut self, ep: u8) -> Option<pool::Rx> {
+		if pool::RX.take_starved() {
+			self.with(|driver| driver.rearm());
+		}
+		pool::RX.receive_from(ep)
+	}
+
+	// The CDC-ACM serial port, its buffers stay with the handle.
+	pub fn serial(&mut self) -> serial::Serial {
+		serial::Serial::new(self)
+	}
+
+	// The boot keyboard.
+	pub fn keyboard(&mut self) -> keyboard::Keyboard {
+		keyboard::Keyboard::new(self)
+	}
+
+	// The touch screen digitizer.
+	pub fn digitizer(&mut self) -> digitizer::Digitizer {
+		digitizer::Digitizer::new(self)
+	}
+
+	// iSerialNumber string the device reports, derived from the unique device ID. The
+	// driver keeps the string, this is a copy of it.
+	pub fn serial_number(&mut self) -> [u8; descriptor::SERIAL_LEN] {
+		self.with(|driver| {
+			let mut serial = [0; descriptor::SERIAL_LEN];
+			serial.copy_from_slice(driver.device().serial_number().as_bytes());
+			serial
+		})
+	}
 }
@@ -0,0 +1,276 @@
+// Statically allocated buffers for packets received on the data endpoints. The interrupt
+// fills a free buffer and queues it, the application takes it from the queue and gives
+// it back by dropping it. Both queues are single producer single consumer rings, so
+// the interrupt and one application context share them without locking. Buffers the
+// interrupt side gives back itself go to a bit mask instead.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// a high speed bulk packet
+pub const PACKET_LEN : usize = 512;
+// number of buffers, a power of two
+pub const POOL_LEN : usize = 8;
+
+pub struct Buffer {
+	ep: u8,
+	len: u16,
+	// in words for the DMA
+	data: [u32; PACKET_LEN / 4],
+}
+
+impl Buffer {
+	pub fn ep(&self) -> u8 {
+		self.ep
+	}
+
+	pub fn data(&self) -> &[u8] {
+		unsafe { ::core::slice::from_raw_parts(self.data.as_ptr() as *const u8, self.len as usize) }
+	}
+
+	// whole buffer, for the interrupt to fill
+	pub fn buf(&mut self) -> &mut [u8] {
+		unsafe { ::core::slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, PACKET_LEN) }
+	}
+
+	pub fn set_received(&mut self, ep: u8, len: usize) {
+		self.ep = ep;
+		self.len = len as u16;
+	}
+}
+
+// Ring of buffer indices. `tail` is only written by the producer, `head` only by the
+// consumer, both count up and wrap.
+struct Ring {
+	slots: UnsafeCell<[u8; POOL_LEN]>,
+	head: AtomicUsize,
+	tail: AtomicUsize,
+}
+
+impl Ring {
+	const fn new(slots: [u8; POOL_LEN], len: usize) -> Ring {
+		Ring {
+			slots: UnsafeCell::new(slots),
+			head: AtomicUsize::new(0),
+			tail: AtomicUsize::new(len),
+		}
+	}
+
+	fn push(&self, index: u8) -> bool {
+		let tail = self.tail.load(Ordering::Relaxed);
+		if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == POOL_LEN {
+			return false;
+		}
+		unsafe { (*self.slots.get())[tail % POOL_LEN] = index; }
+		self.tail.store(tail.wrapping_add(1), Ordering::Release);
+		true
+	}
+
+	fn pop(&self) -> Option<u8> {
+		let head = self.head.load(Ordering::Relaxed);
+		if head == self.tail.load(Ordering::Acquire) {
+			return None;
+		}
+		let index = unsafe { (*self.slots.get())[head % POOL_LEN] };
+		self.head.store(head.wrapping_add(1), Ordering::Release);
+		Some(index)
+	}
+
+	// Consumer side: removes the oldest index `f` accepts, the ones ahead of it move up
+	// and keep their order. The slots from `head` to `tail` belong to the consumer.
+	fn pop_first<F: Fn(u8) -> bool>(&self, f: F) -> Option<u8> {
+		let head = self.head.load(Ordering::Relaxed);
+		let tail = self.tail.load(Ordering::Acquire);
+		let mut i = head;
+		while i != tail {
+			let index = unsafe { (*self.slots.get())[i % POOL_LEN] };
+			if f(index) {
+				while i != head {
+					unsafe {
+						let slots = &mut *self.slots.get();
+						slots[i % POOL_LEN] = slots[i.wrapping_sub(1) % POOL_LEN];
+					}
+					i = i.wrapping_sub(1);
+				}
+				self.head.store(head.wrapping_add(1), Ordering::Release);
+				return Some(index);
+			}
+			i = i.wrapping_add(1);
+		}
+		None
+	}
+}
+
+pub struct Pool {
+	buffers: UnsafeCell<[Buffer; POOL_LEN]>,
+	// interrupt pops, application pushes
+	free: Ring,
+	// interrupt pushes, application pops
+	full: Ring,
+	// an endpoint was left NAKing because no buffer was free
+	starved: AtomicBool,
+	// buffers released by the interrupt side, one bit per index
+	released: AtomicUsize,
+}
+
+unsafe impl Sync for Pool {}
+
+const EMPTY : Buffer = Buffer { ep: 0, len: 0, data: [0; PACKET_LEN / 4] };
+
+// the receive pool of the driver
+pub static RX: Pool = Pool {
+	buffers: UnsafeCell::new([EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY]),
+	free: Ring::new([0, 1, 2, 3, 4, 5, 6, 7], POOL_LEN),
+	full: Ring::new([0; POOL_LEN], 0),
+	starved: AtomicBool::new(false),
+	released: AtomicUsize::new(0),
+};
+
+impl Pool {
+	// Interrupt side: takes a free buffer. None means the endpoint has to keep NAKing
+	// until the application gives buffers back.
+	pub fn alloc(&self) -> Option<u8> {
+		let released = self.released.swap(0, Ordering::AcqRel);
+		if released != 0 {
+			let index = released.trailing_zeros() as usize;
+			self.released.fetch_or(released & !(1 << index), Ordering::AcqRel);
+			return Some(index as u8);
+		}
+		let index = self.free.pop();
+		if index.is_none() {
+			self.starved.store(true, Ordering::Release);
+		}
+		index
+	}
+
+	// Interrupt side: the buffer `index` taken with `alloc`. The caller has to own it.
+	pub unsafe fn buffer(&self, index: u8) -> &mut Buffer {
+		&mut (*self.buffers.get())[index as usize]
+	}
+
+	// Interrupt side: gives back the buffer `index` taken with `alloc` without queueing
+	// it, when the endpoint that held it goes away.
+	pub fn release(&self, index: u8) {
+		self.released.fetch_or(1 << index, Ordering::AcqRel);
+	}
+
+	// Interrupt side: queues the filled buffer `index` for the application.
+	pub fn submit(&self, index: u8) {
+		// every index is either free, queued or owned, so the ring always has room
+		let queued = self.full.push(index);
+		debug_assert!(queued);
+	}
+
+	// Application side: the oldest received packet, goes back to the pool when dropped.
+	pub fn receive(&'static self) -> Option<Rx> {
+		self.full.pop().map(|index| Rx { pool: self, index: index })
+	}
+
+	// Application side: the oldest packet received on endpoint `ep`, the packets of other
+	// endpoints stay queued.
+	pub fn receive_from(&'static self, ep: u8) -> Option<Rx> {
+		let buffers = &self.buffers;
+		self.full.pop_first(|index| unsafe { (*buffers.get())[index as usize].ep() } == ep)
+			.map(|index| Rx { pool: self, index: index })
+	}
+
+	// Application side: whether an endpoint waits for a buffer, cleared by reading it.
+	pub fn take_starved(&self) -> bool {
+		self.starved.swap(false, Ordering::AcqRel)
+	}
+}
+
+// A received packet owned by the application.
+pub struct Rx {
+	pool: &'static Pool,
+	index: u8,
+}
+
+impl Rx {
+	pub fn ep(&self) -> u8 {
+		self.buffer().ep()
+	}
+
+	pub fn data(&self) -> &[u8] {
+		self.buffer().data()
+	}
+
+	fn buffer(&self) -> &Buffer {
+		unsafe { &(*self.pool.buffers.get())[self.index as usize] }
+	}
+}
+
+impl Drop for Rx {
+	fn drop(&mut self) {
+		let freed = self.pool.free.push(self.index);
+		debug_assert!(freed);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use collections::vec::Vec;
+
+	// pools of their own, the driver tests share `RX`
+	static POOL: Pool = Pool {
+		buffers: UnsafeCell::new([EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY]),
+		free: Ring::new([0, 1, 2, 3, 4, 5, 6, 7], POOL_LEN),
+		full: Ring::new([0; POOL_LEN], 0),
+		starved: AtomicBool::new(false),
+		released: AtomicUsize::new(0),
+	};
+	static RELEASE_POOL: Pool = Pool {
+		buffers: UnsafeCell::new([EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY, EMPTY]),
+		free: Ring::new([0, 1, 2, 3, 4, 5, 6, 7], POOL_LEN),
+		full: Ring::new([0; POOL_LEN], 0),
+		starved: AtomicBool::new(false),
+		released: AtomicUsize::new(0),
+	};
+
+	fn submit(ep: u8, data: &[u8]) {
+		let index = POOL.alloc().unwrap();
+		let buffer = unsafe { POOL.buffer(index) };
+		buffer.buf()[..data.len()].copy_from_slice(data);
+		buffer.set_received(ep, data.len());
+		POOL.submit(index);
+	}
+
+	#[test]
+	fn receive_from_leaves_other_endpoints_queued() {
+		// wraps around the rings a few times
+		for _ in 0..5 {
+			submit(2, b"a");
+			submit(1, b"b");
+			submit(2, b"c");
+			submit(1, b"d");
+			assert_eq!(POOL.receive_from(1).unwrap().data(), b"b");
+			assert_eq!(POOL.receive_from(1).unwrap().data(), b"d");
+			assert!(POOL.receive_from(1).is_none());
+			assert_eq!(POOL.receive().unwrap().data(), b"a");
+			assert_eq!(POOL.receive().unwrap().data(), b"c");
+			assert!(POOL.receive().is_none());
+		}
+		assert!(!POOL.take_starved());
+	}
+
+	#[test]
+	fn released_buffers_are_allocated_again() {
+		let mut taken = Vec::new();
+		while let Some(index) = RELEASE_POOL.alloc() {
+			taken.push(index);
+		}
+		assert_eq!(taken.len(), POOL_LEN);
+		assert!(RELEASE_POOL.take_starved());
+		for index in taken.iter() {
+			RELEASE_POOL.release(*index);
+		}
+		let mut again = Vec::new();
+		while let Some(index) = RELEASE_POOL.alloc() {
+			again.push(index);
+		}
+		again.sort();
+		taken.sort();
+		assert_eq!(again, taken);
+	}
+}
@@ -0,0 +1,378 @@
+// USB 2.0 chapter 9 standard requests and the device state they work on.
+
+use super::control::{Reply, Setup};
+use super::descriptor::{self, Builder, Descriptor};
+use super::endpoint;
+
+// bRequest
+pub const GET_STATUS : u8 = 0;
+pub const CLEAR_FEATURE : u8 = 1;
+pub const SET_FEATURE : u8 = 3;
+pub const SET_ADDRESS : u8 = 5;
+pub const GET_DESCRIPTOR : u8 = 6;
+pub const SET_DESCRIPTOR : u8 = 7;
+pub const GET_CONFIGURATION : u8 = 8;
+pub const SET_CONFIGURATION : u8 = 9;
+pub const GET_INTERFACE : u8 = 10;
+pub const SET_INTERFACE : u8 = 11;
+pub const SYNCH_FRAME : u8 = 12;
+
+// feature selectors
+pub const ENDPOINT_HALT : u16 = 0;
+pub const DEVICE_REMOTE_WAKEUP : u16 = 1;
+pub const TEST_MODE : u16 = 2;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Kind {
+	Standard,
+	Class,
+	Vendor,
+	Reserved,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Recipient {
+	Device,
+	Interface,
+	Endpoint,
+	Other,
+	Reserved,
+}
+
+impl Setup {
+	pub fn kind(&self) -> Kind {
+		match (self.request_type >> 5) & 0x3 {
+			0 => Kind::Standard,
+			1 => Kind::Class,
+			2 => Kind::Vendor,
+			_ => Kind::Reserved,
+		}
+	}
+
+	pub fn recipient(&self) -> Recipient {
+		match self.request_type & 0x1f {
+			0 => Recipient::Device,
+			1 => Recipient::Interface,
+			2 => Recipient::Endpoint,
+			3 => Recipient::Other,
+			_ => Recipient::Reserved,
+		}
+	}
+}
+
+// USB device states (USB 2.0 9.1.1), the ones below Default are not visible to the driver
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DeviceState {
+	Default,
+	Address,
+	Configured,
+}
+
+pub const MAX_INTERFACES : usize = 8;
+
+pub struct Device {
+	state: DeviceState,
+	address: u8,
+	configuration: u8,
+	remote_wakeup: bool,
+	// endpoint bit masks, bit n is OUT endpoint n, bit 8+n is IN endpoint n
+	endpoints: u16,
+	halted: u16,
+	isochronous: u16,
+	interfaces: u8,
+	alternate: [u8; MAX_INTERFACES],
+	serial: [u8; descriptor::SERIAL_LEN],
+}
+
+// bit of an endpoint address (bEndpointAddress) in the endpoint masks
+fn ep_bit(address: u16) -> u16 {
+	let n = address & 0x7;
+	if address & 0x80 != 0 { 1 << (8 + n) } else { 1 << n }
+}
+
+impl Device {
+	pub fn new() -> Device {
+		Device {
+			state: DeviceState::Default,
+			address: 0,
+			configuration: 0,
+			remote_wakeup: false,
+			endpoints: ep_bit(0x00) | ep_bit(0x80),
+			halted: 0,
+			isochronous: 0,
+			interfaces: 0,
+			alternate: [0; MAX_INTERFACES],
+			serial: [b'0'; descriptor::SERIAL_LEN],
+		}
+	}
+
+	// bus reset, keeps the serial number
+	pub fn reset(&mut self) {
+		let serial = self.serial;
+		*self = Device::new();
+		self.serial = serial;
+	}
+
+	pub fn set_serial_number(&mut self, uid: [u32; 3]) {
+		self.serial = descriptor::serial_number(uid);
+	}
+
+	pub fn serial_number(&self) -> &str {
+		// only hex digits
+		::core::str::from_utf8(&self.serial).unwrap()
+	}
+
+	pub fn state(&self) -> DeviceState {
+		self.state
+	}
+
+	pub fn address(&self) -> u8 {
+		self.address
+	}
+
+	pub fn configuration(&self) -> u8 {
+		self.configuration
+	}
+
+	pub fn remote_wakeup(&self) -> bool {
+		self.remote_wakeup
+	}
+
+	pub fn is_halted(&self, address: u8) -> bool {
+		self.halted & ep_bit(address as u16) != 0
+	}
+
+	pub fn set_halted(&mut self, address: u8, halted: bool) {
+		let bit = ep_bit(address as u16);
+		if halted { self.halted |= bit } else { self.halted &= !bit }
+	}
+
+	// Takes the interfaces and endpoints from the configuration tree `tree`.
+	fn configure(&mut self, tree: &[Descriptor]) {
+		self.deconfigure();
+		for desc in tree {
+			match *desc {
+				Descriptor::Configuration { value, .. } => self.configuration = value,
+				Descriptor::Interface { number, .. } => {
+					self.interfaces = ::core::cmp::max(self.interfaces, number + 1);
+				},
+				Descriptor::Endpoint { address, attributes, .. } => {
+					let bit = ep_bit(address as u16);
+					self.endpoints |= bit;
+					if attributes & 0x3 == descriptor::ISOCHRONOUS {
+						self.isochronous |= bit;
+					}
+				},
+				Descriptor::Association { .. } | Descriptor::Class { .. } | Descriptor::Hid { .. } => (),
+			}
+		}
+		self.state = DeviceState::Configured;
+	}
+
+	fn deconfigure(&mut self) {
+		self.configuration = 0;
+		self.interfaces = 0;
+		self.endpoints = ep_bit(0x00) | ep_bit(0x80);
+		self.halted = 0;
+		self.isochronous = 0;
+		self.alternate = [0; MAX_INTERFACES];
+		self.state = DeviceState::Address;
+	}
+
+	// `address` is wIndex of an endpoint request, the endpoint has to exist in the core
+	// and in the configuration
+	fn has_endpoint(&self, address: u16) -> bool {
+		address & !0x8f == 0 && address & 0xf < endpoint::NUM_ENDPOINTS as u16
+			&& self.endpoints & ep_bit(address) != 0
+	}
+
+	fn has_interface(&self, interface: u16) -> bool {
+		interface < self.interfaces as u16
+	}
+
+	// Standard requests of any recipient, everything else is stalled.
+	pub fn request(&mut self, setup: &Setup, buf: &mut [u8]) -> Reply {
+		if setup.kind() != Kind::Standard {
+			return Reply::Stall;
+		}
+		let configured = self.state == DeviceState::Configured;
+		match (setup.request, setup.recipient()) {
+			(GET_STATUS, recipient) if setup.length == 2 => {
+				let status = match recipient {
+					Recipient::Device => 0x1 | ((self.remote_wakeup as u8) << 1), // self powered
+					Recipient::Interface if configured && self.has_interface(setup.index) => 0,
+					Recipient::Endpoint if self.has_endpoint(setup.index) => self.is_halted(setup.index as u8) as u8,
+					_ => return Reply::Stall,
+				};
+				buf[0] = status;
+				buf[1] = 0;
+				Reply::Data(2)
+			},
+			(CLEAR_FEATURE, recipient) | (SET_FEATURE, recipient) => {
+				let set = setup.request == SET_FEATURE;
+				match (recipient, setup.value) {
+					(Recipient::Device, DEVICE_REMOTE_WAKEUP) => {
+						self.remote_wakeup = set;
+						Reply::Ack
+					},
+					(Recipient::Endpoint, ENDPOINT_HALT) if self.has_endpoint(setup.index) => {
+						// the control endpoint is only halted by protocol stalls
+						if setup.index & 0x7f != 0 {
+							self.set_halted(setup.index as u8, set);
+						}
+						Reply::Ack
+					},
+					// TEST_MODE and interface features are not supported
+					_ => Reply::Stall,
+				}
+			},
+			(SET_ADDRESS, Recipient::Device) if setup.value <= 127 && !configured => {
+				self.address = setup.value as u8;
+				self.state = if self.address == 0 { DeviceState::Default } else { DeviceState::Address };
+				Reply::Ack
+			},
+			(GET_DESCRIPTOR, Recipient::Device) => {
+				// wIndex is the LANGID for strings, all strings exist in every supported language
+				let (desc_type, index) = ((setup.value >> 8) as u8, setup.value as u8);
+				let len = match (desc_type, index) {
+					(descriptor::STRING, descriptor::STR_SERIAL) => Builder::new(buf).string(self.serial_number()).finish(),
+					_ => descriptor::get(desc_type, index, buf),
+				};
+				match len {
+					Some(len) => Reply::Data(len),
+					None => Reply::Stall,
+				}
+			},
+			// class descriptors of an interface, like the report descriptor of a HID interface
+			(GET_DESCRIPTOR, Recipient::Interface) if configured && self.has_interface(setup.index) => {
+				match descriptor::interface(descriptor::TREE, setup.index as u8, (setup.value >> 8) as u8, buf) {
+					Some(len) => Reply::Data(len),
+					None => Reply::Stall,
+				}
+			},
+			(GET_CONFIGURATION, Recipient::Device) if setup.length == 1 => {
+				buf[0] = self.configuration;
+				Reply::Data(1)
+			},
+			(SET_CONFIGURATION, Recipient::Device) if self.state != DeviceState::Default => {
+				let value = descriptor::configuration_value(descriptor::TREE);
+				match setup.value {
+					0 => {
+						self.deconfigure();
+						Reply::Ack
+					},
+					v if v <= 0xff && Some(v as u8) == value => {
+						self.configure(descriptor::TREE);
+						Reply::Ack
+					},
+					_ => Reply::Stall,
+				}
+			},
+			(GET_INTERFACE, Recipient::Interface) if configured && self.has_interface(setup.index) => {
+				buf[0] = self.alternate[setup.index as usize];
+				Reply::Data(1)
+			},
+			(SET_INTERFACE, Recipient::Interface) if configured && self.has_interface(setup.index) => {
+				// only the default alternate setting exists
+				if setup.value != 0 {
+					return Reply::Stall;
+				}
+				self.alternate[setup.index as usize] = 0;
+				Reply::Ack
+			},
+			(SYNCH_FRAME, Recipient::Endpoint) if configured && self.has_endpoint(setup.index)
+				&& self.isochronous & ep_bit(setup.index) != 0 => {
+				buf[0] = 0;
+				buf[1] = 0;
+				Reply::Data(2)
+			},
+			// SET_DESCRIPTOR is optional
+			_ => Reply::Stall,
+		}
+	}
+
+	// Data stage of an OUT request that `request` accepted.
+	#[allow(unused_variables)]
+	pub fn data_out(&mut self, setup: &Setup, data: &[u8]) -> Reply {
+		// no standard request with an OUT data stage is supported
+		Reply::Stall
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn request(device: &mut Device, setup: [u8; 8]) -> Reply {
+		let mut buf = [0u8; 64];
+		device.request(&Setup::parse(&setup), &mut buf)
+	}
+
+	fn configured() -> Device {
+		let mut device = Device::new();
+		assert_eq!(request(&mut device, [0x00, SET_ADDRESS, 1, 0, 0, 0, 0, 0]), Reply::Ack);
+		assert_eq!(request(&mut device, [0x00, SET_CONFIGURATION, 1, 0, 0, 0, 0, 0]), Reply::Ack);
+		device
+	}
+
+	#[test]
+	fn set_configuration_takes_the_configuration_value() {
+		let mut device = Device::new();
+		assert_eq!(request(&mut device, [0x00, SET_ADDRESS, 1, 0, 0, 0, 0, 0]), Reply::Ack);
+		assert_eq!(request(&mut device, [0x00, SET_CONFIGURATION, 2, 0, 0, 0, 0, 0]), Reply::Stall);
+		// the high byte of wValue is reserved
+		assert_eq!(request(&mut device, [0x00, SET_CONFIGURATION, 1, 1, 0, 0, 0, 0]), Reply::Stall);
+		assert_eq!(device.state(), DeviceState::Address);
+		assert_eq!(request(&mut device, [0x00, SET_CONFIGURATION, 1, 0, 0, 0, 0, 0]), Reply::Ack);
+		assert_eq!(device.configuration(), 1);
+	}
+
+	#[test]
+	fn device_qualifier_and_other_speed_configuration() {
+		let mut device = Device::new();
+		let mut buf = [0u8; 64];
+		let qualifier = Setup::parse(&[0x80, GET_DESCRIPTOR, 0, descriptor::DEVICE_QUALIFIER, 0, 0, 10, 0]);
+		assert_eq!(device.request(&qualifier, &mut buf), Reply::Data(10));
+		let mut buf = [0u8; 512];
+		let other = Setup::parse(&[0x80, GET_DESCRIPTOR, 0, descriptor::OTHER_SPEED_CONFIGURATION, 0, 0, 0xff, 0x01]);
+		match device.request(&other, &mut buf) {
+			Reply::Data(len) => assert!(len > 9),
+			reply => panic!("{:?}", reply),
+		}
+		assert_eq!(buf[1], descriptor::OTHER_SPEED_CONFIGURATION);
+	}
+
+	#[test]
+	fn endpoint_status() {
+		let mut device = configured();
+		let mut buf = [0u8; 2];
+		let setup = Setup::parse(&[0x82, GET_STATUS, 0, 0, 0x81, 0, 2, 0]);
+		assert_eq!(device.request(&setup, &mut buf), Reply::Data(2));
+		assert_eq!(buf, [0, 0]);
+		assert_eq!(request(&mut device, [0x02, SET_FEATURE, 0, 0, 0x81, 0, 0, 0]), Reply::Ack);
+		assert!(device.is_halted(0x81));
+		assert_eq!(device.request(&setup, &mut buf), Reply::Data(2));
+		assert_eq!(buf, [1, 0]);
+	}
+
+	#[test]
+	fn endpoints_beyond_the_core_do_not_exist() {
+		let mut device = configured();
+		for &address in [0x08u8, 0x88, 0x0f, 0x8f].iter() {
+			assert_eq!(request(&mut device, [0x82, GET_STATUS, 0, 0, address, 0, 2, 0]), Reply::Stall);
+			assert_eq!(request(&mut device, [0x02, SET_FEATURE, 0, 0, address, 0, 0, 0]), Reply::Stall);
+			assert_eq!(request(&mut device, [0x02, CLEAR_FEATURE, 0, 0, address, 0, 0, 0]), Reply::Stall);
+		}
+		assert!(!device.is_halted(0x80));
+		assert!(!device.is_halted(0x00));
+		// the high byte of wIndex is reserved
+		assert_eq!(request(&mut device, [0x82, GET_STATUS, 0, 0, 0x81, 0x01, 2, 0]), Reply::Stall);
+	}
+
+	#[test]
+	fn endpoints_outside_the_configuration_do_not_exist() {
+		let mut device = Device::new();
+		assert_eq!(request(&mut device, [0x82, GET_STATUS, 0, 0, 0x80, 0, 2, 0]), Reply::Data(2));
+		assert_eq!(request(&mut device, [0x82, GET_STATUS, 0, 0, 0x81, 0, 2, 0]), Reply::Stall);
+	}
+}
@@ -0,0 +1,218 @@
+// Byte stream over the CDC-ACM serial port. Received data and data waiting for the data
+// IN endpoint are buffered in the `Usb` handle, so reading and writing never wait for
+// the bus.
+
+use core::fmt;
+use super::hw::Hardware;
+use super::driver::Driver;
+use super::pool::{self, Rx, PACKET_LEN};
+use super::cdc;
+use super::request::DeviceState;
+#[cfg(not(feature = "usbip"))]
+use super::Usb;
+
+pub const RING_LEN : usize = 1024;
+// `write_all` gives up after this many calls in a row that took nothing
+pub const WRITE_ATTEMPTS : u32 = 100_000;
+
+// byte ring buffer
+struct Ring {
+	buf: [u8; RING_LEN],
+	start: usize,
+	len: usize,
+}
+
+impl Ring {
+	fn new() -> Ring {
+		Ring { buf: [0; RING_LEN], start: 0, len: 0 }
+	}
+
+	fn clear(&mut self) {
+		self.start = 0;
+		self.len = 0;
+	}
+
+	// Appends as much of `data` as fits, returns how much that was.
+	fn write(&mut self, data: &[u8]) -> usize {
+		let count = ::core::cmp::min(data.len(), RING_LEN - self.len);
+		for (i, byte) in data[..count].iter().enumerate() {
+			self.buf[(self.start + self.len + i) % RING_LEN] = *byte;
+		}
+		self.len += count;
+		count
+	}
+
+	// Copies the oldest bytes to `buf` without removing them, returns how many.
+	fn peek(&self, buf: &mut [u8]) -> usize {
+		let count = ::core::cmp::min(buf.len(), self.len);
+		for (i, byte) in buf[..count].iter_mut().enumerate() {
+			*byte = self.buf[(self.start + i) % RING_LEN];
+		}
+		count
+	}
+
+	fn consume(&mut self, count: usize) {
+		let count = ::core::cmp::min(count, self.len);
+		self.start = (self.start + count) % RING_LEN;
+		self.len -= count;
+	}
+
+	fn read(&mut self, buf: &mut [u8]) -> usize {
+		let count = self.peek(buf);
+		self.consume(count);
+		count
+	}
+}
+
+// Buffers of the serial port, kept in the `Usb` handle.
+pub struct Buffers {
+	rx: Ring,
+	tx: Ring,
+	// received packet that did not fit into `rx` yet and how much of it was taken
+	pending: Option<(Rx, usize)>,
+	// the last IN packet was full, the host waits for a short one to end the transfer
+	zlp: bool,
+}
+
+impl Buffers {
+	pub fn new() -> Buffers {
+		Buffers { rx: Ring::new(), tx: Ring::new(), pending: None, zlp: false }
+	}
+
+	// The host has the port open (DTR set), otherwise nothing is sent.
+	pub fn is_open<H: Hardware>(&self, driver: &Driver<H>) -> bool {
+		driver.device().state() == DeviceState::Configured && driver.acm().dtr()
+	}
+
+	// Moves received packets into the receive buffer and starts the next IN packet. A
+	// transfer that ends with a full packet is closed with a zero length packet. While
+	// the port is closed queued data is dropped.
+	pub fn poll<H: Hardware>(&mut self, driver: &mut Driver<H>) {
+		self.receive(driver);
+		if !self.is_open(driver) {
+			self.tx.clear();
+			self.zlp = false;
+			return;
+		}
+		let mut packet = [0u8; PACKET_LEN];
+		let len = self.tx.peek(&mut packet);
+		if len > 0 {
+			let sent = driver.send(cdc::DATA_IN_EP, &packet[..len]);
+			if sent > 0 {
+				self.tx.consume(sent);
+				self.zlp = sent == cdc::DATA_MPS as usize;
+			}
+		} else if self.zlp && driver.send_zlp(cdc::DATA_IN_EP) {
+			self.zlp = false;
+		}
+	}
+
+	fn receive<H: Hardware>(&mut self, driver: &mut Driver<H>) {
+		loop {
+			if self.pending.is_none() {
+				if pool::RX.take_starved() {
+					driver.rearm();
+				}
+				self.pending = pool::RX.receive_from(cdc::DATA_OUT_EP).map(|rx| (rx, 0));
+			}
+			let done = match self.pending {
+				Some((ref rx, ref mut taken)) => {
+					*taken += self.rx.write(&rx.data()[*taken..]);
+					*taken == rx.data().len()
+				},
+				None => return,
+			};
+			if !done {
+				// the receive buffer is full, the packet stays in the pool until there is
+				// room and the endpoint NAKs once the pool runs dry
+				return;
+			}
+			self.pending = None;
+		}
+	}
+
+	// Reads up to `buf.len()` received bytes, returns how many there were.
+	pub fn read<H: Hardware>(&mut self, driver: &mut Driver<H>, buf: &mut [u8]) -> usize {
+		self.poll(driver);
+		self.rx.read(buf)
+	}
+
+	// Queues as much of `data` as fits into the transmit buffer, returns how much that
+	// was. None while the port is closed.
+	pub fn write<H: Hardware>(&mut self, driver: &mut Driver<H>, data: &[u8]) -> Option<usize> {
+		self.poll(driver);
+		if !self.is_open(driver) {
+			return None;
+		}
+		let count = self.tx.write(data);
+		self.poll(driver);
+		Some(count)
+	}
+}
+
+// Hands all of `data` to `write`, which returns how much it took or None once the port
+// is closed. Fails when the port closes or the host stops reading, so a console without
+// a terminal does not hang.
+pub fn write_all<F>(mut data: &[u8], mut write: F) -> fmt::Result where F: FnMut(&[u8]) -> Option<usize> {
+	let mut attempts = 0;
+	while !data.is_empty() {
+		match write(data) {
+			None => return Err(fmt::Error),
+			Some(0) => {
+				attempts += 1;
+				if attempts == WRITE_ATTEMPTS {
+					return Err(fmt::Error);
+				}
+			},
+			Some(count) => {
+				data = &data[count..];
+				attempts = 0;
+			},
+		}
+	}
+	Ok(())
+}
+
+// Handle to the serial port, get it from `Usb::serial`.
+#[cfg(not(feature = "usbip"))]
+pub struct Serial<'a> {
+	usb: &'a mut Usb,
+}
+
+#[cfg(not(feature = "usbip"))]
+impl<'a> Serial<'a> {
+	pub fn new(usb: &'a mut Usb) -> Serial<'a> {
+		Serial { usb: usb }
+	}
+
+	// The host has the port open (DTR set), otherwise nothing is sent.
+	pub fn is_open(&mut self) -> bool {
+		self.usb.with_port(|port, driver| port.is_open(driver))
+	}
+
+	// Moves received packets into the receive buffer and starts the next IN packet.
+	pub fn poll(&mut self) {
+		self.usb.with_port(|port, driver| port.poll(driver))
+	}
+
+	// Reads up to `buf.len()` received bytes, returns how many there were.
+	pub fn read(&mut self, buf: &mut [u8]) -> usize {
+		self.usb.with_port(|port, driver| port.read(driver, buf))
+	}
+
+	// Queues as much of `data` as fits into the transmit buffer, returns how much that
+	// was. Nothing is taken while the port is closed.
+	pub fn write(&mut self, data: &[u8]) -> usize {
+		self.usb.with_port(|port, driver| port.write(driver, data)).unwrap_or(0)
+	}
+}
+
+#[cfg(not(feature = "usbip"))]
+impl<'a> fmt::Write for Serial<'a> {
+	// Waits for room in the transmit buffer. Once the port is closed or the host stops
+	// reading the rest of `s` is dropped and an error returned.
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let usb = &mut self.usb;
+		write_all(s.as_bytes(), |data| usb.with_port(|port, driver| port.write(driver, data)))
+	}
+}
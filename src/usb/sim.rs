@@ -0,0 +1,839 @@
+use board::otg_hs_global::OtgHsGlobal;
+use board::otg_hs_device::OtgHsDevice;
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+use super::hw::{Hardware, Status};
+use super::driver::Driver;
+
+// GINTSTS
+const MMIS : u32 = 1 << 1;
+const SOF : u32 = 1 << 3;
+const RXFLVL : u32 = 1 << 4;
+const BOUTNAKEFF : u32 = 1 << 7;
+const USBRST : u32 = 1 << 12;
+const ENUMDNE : u32 = 1 << 13;
+const IEPINT : u32 = 1 << 18;
+const OEPINT : u32 = 1 << 19;
+const W1C : u32 = 0b11110000011100001111110000001010;
+// DOEPINTx / DIEPINTx
+const XFRC : u32 = 1 << 0;
+const EPDISD : u32 = 1 << 1;
+const STUP : u32 = 1 << 3;
+const B2BSTUP : u32 = 1 << 6;
+const TXFE : u32 = 1 << 7;
+// DIEPCTLx / DOEPCTLx
+const STALL : u32 = 1 << 21;
+const EPDIS : u32 = 1 << 30;
+const EPENA : u32 = 1 << 31;
+// GRXSTSP packet status
+const GLOBAL_OUT_NAK : u8 = 0x1;
+const OUT_DATA : u8 = 0x2;
+const OUT_DONE : u8 = 0x3;
+const SETUP_DONE : u8 = 0x4;
+const SETUP_DATA : u8 = 0x6;
+// endpoints per direction, including endpoint 0
+const NUM_ENDPOINTS : usize = 8;
+// depth of TX FIFO 0 in words
+const TX0_DEPTH : u16 = 0x200;
+// upper bound of handler runs per `run`, guards against interrupt storms
+const MAX_DISPATCH : usize = 64;
+// unique device ID of the simulated chip
+const UNIQUE_ID : [u32; 3] = [0x0030_0042, 0x3436_5113, 0x3332_3437];
+
+// Behavioural model of the parts of the Synopsys OTG HS device core the driver relies
+// on, seen from the bus side: the test plays the host by injecting tokens and pulling
+// IN packets, `Driver::run` lets the driver react to the resulting interrupts. Endpoint
+// 0 and the data endpoints are modelled in slave mode, the host fetches every IN packet
+// as soon as it is complete unless the test paused the endpoint. The DMA, the NAK bits
+// and bus timing are not modelled.
+pub struct Simulator {
+	global: OtgHsGlobal,
+	device: OtgHsDevice,
+	// latched write-1-to-clear bits of GINTSTS
+	gintsts: u32,
+	doepint: [u32; NUM_ENDPOINTS],
+	diepint: [u32; NUM_ENDPOINTS],
+	rx_status: VecDeque<u32>,
+	// shared RX FIFO, in words
+	rx_fifo: VecDeque<u32>,
+	// words of the IN packet currently being written by the driver, per TX FIFO
+	tx_fifo: [Vec<u32>; NUM_ENDPOINTS],
+	in_packets: [VecDeque<Vec<u8>>; NUM_ENDPOINTS],
+	// the global OUT NAK is in effect
+	out_nak: bool,
+	// the host does not poll these IN endpoints
+	in_paused: [bool; NUM_ENDPOINTS],
+}
+
+impl Simulator {
+	pub fn new() -> Simulator {
+		let mut sim = Simulator {
+			global: unsafe { ::core::mem::zeroed() },
+			device: unsafe { ::core::mem::zeroed() },
+			gintsts: 0,
+			doepint: [0; NUM_ENDPOINTS],
+			diepint: [0; NUM_ENDPOINTS],
+			rx_status: VecDeque::new(),
+			rx_fifo: VecDeque::new(),
+			tx_fifo: [Vec::new(), Vec::new(), Vec::new(), Vec::new(),
+				Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+			in_packets: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new(),
+				VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+			out_nak: false,
+			in_paused: [false; NUM_ENDPOINTS],
+		};
+		sim.sync();
+		sim
+	}
+
+	// Host drives a bus reset.
+	pub fn bus_reset(&mut self) {
+		self.rx_status.clear();
+		self.rx_fifo.clear();
+		for n in 0..NUM_ENDPOINTS {
+			self.tx_fifo[n].clear();
+			self.in_packets[n].clear();
+			self.doepint[n] = 0;
+			self.diepint[n] = 0;
+		}
+		self.gintsts |= USBRST;
+		self.sync();
+	}
+
+	// Speed enumeration finished, `speed` is the DSTS.ENUMSPD encoding.
+	pub fn enumeration_done(&mut self, speed: u8) {
+		let mut dsts = self.device.otg_hs_dsts.read();
+		dsts.bits = (dsts.bits & !(0x3 << 1)) | ((speed as u32 & 0x3) << 1);
+		self.device.otg_hs_dsts.write(dsts);
+		self.gintsts |= ENUMDNE;
+		self.sync();
+	}
+
+	// The host starts a microframe.
+	pub fn sof(&mut self) {
+		self.gintsts |= SOF;
+		self.sync();
+	}
+
+	// Core detected an access in the wrong mode.
+	pub fn mode_mismatch(&mut self) {
+		self.gintsts |= MMIS;
+		self.sync();
+	}
+
+	// A SETUP transaction on endpoint 0. The core always accepts it, STUPCNT counts the
+	// back-to-back SETUPs the driver has room for.
+	pub fn setup(&mut self, data: &[u8; 8]) {
+		let stupcnt = self.device.otg_hs_doeptsiz0.read().stupcnt();
+		if stupcnt == 0 {
+			self.doepint[0] |= B2BSTUP;
+		} else {
+			self.device.otg_hs_doeptsiz0.update(|r| r.set_stupcnt(stupcnt - 1));
+		}
+		// the host went on to the data or status stage of the previous SETUP only if its
+		// setup stage done entry was popped, otherwise this one replaces it
+		let done = (SETUP_DONE as u32) << 17;
+		if self.rx_status.back() == Some(&done) {
+			self.rx_status.pop_back();
+		}
+		self.push_rx(0, SETUP_DATA, 0, data);
+		self.push_rx(0, SETUP_DONE, 0, &[]);
+		self.sync();
+	}
+
+	// An OUT data packet for endpoint `ep`. Returns false if the core would not take it:
+	// the endpoint is not enabled or the global OUT NAK is in effect (NAK), it is halted
+	// (STALL) or the packet is larger than the max packet size.
+	pub fn out(&mut self, ep: u8, data: &[u8]) -> bool {
+		let n = ep & 0x7f;
+		if n as usize >= NUM_ENDPOINTS || self.out_nak {
+			return false;
+		}
+		let ctl = self.out_ctl(n);
+		if ctl & EPENA == 0 || ctl & STALL != 0 || data.len() > self.mps(n, ctl) {
+			return false;
+		}
+		self.push_rx(n, OUT_DATA, 0, data);
+		self.push_rx(n, OUT_DONE, 0, &[]);
+		self.sync();
+		true
+	}
+
+	// The host stops or resumes polling IN endpoint `ep`, meanwhile packets wait in the
+	// TX FIFO.
+	pub fn pause_in(&mut self, ep: u8, paused: bool) {
+		if let Some(p) = self.in_paused.get_mut((ep & 0x7f) as usize) {
+			*p = paused;
+		}
+		self.sync();
+	}
+
+	// Next IN packet the host received on endpoint `ep`.
+	pub fn pop_in(&mut self, ep: u8) -> Option<Vec<u8>> {
+		self.in_packets.get_mut((ep & 0x7f) as usize).and_then(|packets| packets.pop_front())
+	}
+
+	// Whether the host gets STALL on endpoint `address` (bEndpointAddress).
+	pub fn stalled(&mut self, address: u8) -> bool {
+		let n = address & 0x7f;
+		if n as usize >= NUM_ENDPOINTS {
+			return false;
+		}
+		let ctl = if address & 0x80 != 0 { self.in_ctl(n) } else { self.out_ctl(n) };
+		ctl & STALL != 0
+	}
+
+	// address the core answers to (DCFG.DAD)
+	pub fn address(&self) -> u8 {
+		self.device.otg_hs_dcfg.read().dad()
+	}
+
+	pub fn pending(&self) -> bool {
+		let gintmsk = self.global.otg_hs_gintmsk.read().bits;
+		self.global.otg_hs_gintsts.read().bits & gintmsk != 0
+	}
+
+	fn push_rx(&mut self, ep: u8, status: u8, dpid: u8, data: &[u8]) {
+		let grxstsp = (ep as u32 & 0xf)
+			| ((data.len() as u32 & 0x7ff) << 4)
+			| ((dpid as u32 & 0x3) << 15)
+			| ((status as u32 & 0xf) << 17);
+		self.rx_status.push_back(grxstsp);
+		for chunk in data.chunks(4) {
+			let mut word = 0u32;
+			for (i, byte) in chunk.iter().enumerate() {
+				word |= (*byte as u32) << (i*8);
+			}
+			self.rx_fifo.push_back(word);
+		}
+	}
+
+	// DIEPCTLx of endpoint `n` 0 to 7
+	fn in_ctl(&mut self, n: u8) -> u32 {
+		match n {
+			0 => self.device.otg_hs_diepctl0.read().bits,
+			n => diepctl!(self.device, n, |r| r.read().bits).unwrap_or(0),
+		}
+	}
+
+	fn set_in_ctl(&mut self, n: u8, bits: u32) {
+		match n {
+			0 => self.device.otg_hs_diepctl0.update(|r| r.bits = bits),
+			n => { diepctl!(self.device, n, |r| r.update(|r| r.bits = bits)); },
+		}
+	}
+
+	// DOEPCTLx of endpoint `n` 0 to 7
+	fn out_ctl(&mut self, n: u8) -> u32 {
+		match n {
+			0 => self.device.otg_hs_doepctl0.read().bits,
+			n => doepctl!(self.device, n, |r| r.read().bits).unwrap_or(0),
+		}
+	}
+
+	fn set_out_ctl(&mut self, n: u8, bits: u32) {
+		match n {
+			0 => self.device.otg_hs_doepctl0.update(|r| r.bits = bits),
+			n => { doepctl!(self.device, n, |r| r.update(|r| r.bits = bits)); },
+		}
+	}
+
+	// (XFRSIZ, PKTCNT) of DIEPTSIZx
+	fn in_size(&mut self, n: u8) -> (usize, u32) {
+		match n {
+			0 => {
+				let tsiz = self.device.otg_hs_dieptsiz0.read();
+				(tsiz.xfrsiz() as usize, tsiz.pktcnt() as u32)
+			},
+			n => dieptsiz!(self.device, n, |r| {
+				let tsiz = r.read();
+				(tsiz.xfrsiz() as usize, tsiz.pktcnt() as u32)
+			}).unwrap_or((0, 0)),
+		}
+	}
+
+	fn set_in_size(&mut self, n: u8, xfrsiz: usize, pktcnt: u32) {
+		match n {
+			0 => self.device.otg_hs_dieptsiz0.update(|r| {
+				r.set_xfrsiz(xfrsiz as u8);
+				r.set_pktcnt(pktcnt as u8);
+			}),
+			n => { dieptsiz!(self.device, n, |r| r.update(|r| {
+				r.set_xfrsiz(xfrsiz as u32);
+				r.set_pktcnt(pktcnt as u16);
+			})); },
+		}
+	}
+
+	// max packet size from the MPSIZ field of the control register `ctl` of endpoint `n`
+	fn mps(&self, n: u8, ctl: u32) -> usize {
+		if n != 0 {
+			return (ctl & 0x7ff) as usize;
+		}
+		match ctl & 0x3 {
+			0 => 64,
+			1 => 32,
+			2 => 16,
+			_ => 8,
+		}
+	}
+
+	// The host fetches everything the driver has prepared for IN endpoint `n`.
+	fn transmit(&mut self, n: u8) {
+		let fifo = n as usize;
+		if self.in_paused[fifo] {
+			return;
+		}
+		loop {
+			let ctl = self.in_ctl(n);
+			if ctl & EPENA == 0 || ctl & STALL != 0 {
+				return;
+			}
+			let (xfrsiz, pktcnt) = self.in_size(n);
+			if pktcnt == 0 {
+				return;
+			}
+			let len = ::core::cmp::min(xfrsiz, self.mps(n, ctl));
+			if self.tx_fifo[fifo].len() * 4 < len {
+				return;
+			}
+			let mut packet = Vec::with_capacity(len);
+			for i in 0..len {
+				packet.push((self.tx_fifo[fifo][i / 4] >> ((i % 4) * 8)) as u8);
+			}
+			self.tx_fifo[fifo].drain(..(len + 3) / 4);
+			self.in_packets[fifo].push_back(packet);
+
+			self.set_in_size(n, xfrsiz - len, pktcnt - 1);
+			if pktcnt == 1 {
+				self.set_in_ctl(n, ctl & !EPENA);
+				self.diepint[fifo] |= XFRC;
+			}
+		}
+	}
+
+	// EPDIS takes effect right away: the endpoint is disabled and EPDISD raised.
+	fn disable(&mut self, n: u8) {
+		let ctl = self.in_ctl(n);
+		if ctl & EPDIS != 0 {
+			self.set_in_ctl(n, ctl & !(EPDIS | EPENA));
+			self.diepint[n as usize] |= EPDISD;
+		}
+		let ctl = self.out_ctl(n);
+		if ctl & EPDIS != 0 {
+			self.set_out_ctl(n, ctl & !(EPDIS | EPENA));
+			self.doepint[n as usize] |= EPDISD;
+		}
+	}
+
+	// Recompute the registers derived from the core state.
+	fn sync(&mut self) {
+		for n in 0..NUM_ENDPOINTS as u8 {
+			self.disable(n);
+			self.transmit(n);
+		}
+
+		let mut dtxfsts = self.device.otg_hs_dtxfsts0.read();
+		dtxfsts.bits = (TX0_DEPTH - self.tx_fifo[0].len() as u16) as u32;
+		self.device.otg_hs_dtxfsts0.write(dtxfsts);
+
+		let diepmsk = self.device.otg_hs_diepmsk.read().bits;
+		let doepmsk = self.device.otg_hs_doepmsk.read().bits;
+		let empmsk = self.device.otg_hs_diepempmsk.read().ineptxfem() as u32;
+		let mut daint_bits = 0;
+		for n in 0..NUM_ENDPOINTS {
+			if self.tx_fifo[n].is_empty() {
+				self.diepint[n] |= TXFE;
+			} else {
+				self.diepint[n] &= !TXFE;
+			}
+			let (diepint, doepint) = (self.diepint[n], self.doepint[n]);
+			status_reg!(self.global, self.device, Status::Diepint(n as u8), |r| {
+				let mut value = r.read();
+				value.bits = diepint;
+				r.write(value);
+			});
+			status_reg!(self.global, self.device, Status::Doepint(n as u8), |r| {
+				let mut value = r.read();
+				value.bits = doepint;
+				r.write(value);
+			});
+			if diepint & diepmsk != 0 || (diepint & TXFE != 0 && empmsk & (1 << n) != 0) {
+				daint_bits |= 1 << n;
+			}
+			if doepint & doepmsk != 0 {
+				daint_bits |= 1 << (n + 16);
+			}
+		}
+		let mut daint = self.device.otg_hs_daint.read();
+		daint.bits = daint_bits;
+		self.device.otg_hs_daint.write(daint);
+
+		let daintmsk = self.device.otg_hs_daintmsk.read().bits;
+		let mut gintsts = self.gintsts & W1C;
+		if !self.rx_status.is_empty() {
+			gintsts |= RXFLVL;
+		}
+		if self.out_nak {
+			gintsts |= BOUTNAKEFF;
+		}
+		if daint_bits & daintmsk & 0xffff != 0 {
+			gintsts |= IEPINT;
+		}
+		if daint_bits & daintmsk & 0xffff0000 != 0 {
+			gintsts |= OEPINT;
+		}
+		let mut reg = self.global.otg_hs_gintsts.read();
+		reg.bits = gintsts;
+		self.global.otg_hs_gintsts.write(reg);
+	}
+}
+
+impl Driver<Simulator> {
+	// Let the driver handle interrupts until none are pending. Returns the number of
+	// handler runs.
+	pub fn run(&mut self) -> usize {
+		let mut runs = 0;
+		self.hw().sync();
+		while self.hw().pending() && runs < MAX_DISPATCH {
+			self.poll();
+			self.hw().sync();
+			runs += 1;
+		}
+		runs
+	}
+}
+
+impl Hardware for Simulator {
+	fn global(&mut self) -> &mut OtgHsGlobal {
+		&mut self.global
+	}
+
+	fn device(&mut self) -> &mut OtgHsDevice {
+		&mut self.device
+	}
+
+	fn pop_rx_status(&mut self) -> u32 {
+		let grxstsp = self.rx_status.pop_front().unwrap_or(0);
+		let n = (grxstsp & 0xf) as u8;
+		let status = ((grxstsp >> 17) & 0xf) as u8;
+		match status {
+			SETUP_DONE if n == 0 => self.doepint[0] |= STUP,
+			GLOBAL_OUT_NAK => self.out_nak = true,
+			OUT_DONE if (n as usize) < NUM_ENDPOINTS => {
+				let ctl = self.out_ctl(n);
+				self.set_out_ctl(n, ctl & !EPENA);
+				self.doepint[n as usize] |= XFRC;
+			},
+			_ => (),
+		}
+		self.sync();
+		grxstsp
+	}
+
+	fn read_fifo(&mut self) -> u32 {
+		self.rx_fifo.pop_front().unwrap_or(0)
+	}
+
+	fn write_fifo(&mut self, ep: u8, word: u32) {
+		self.tx_fifo[ep as usize].push(word);
+		self.sync();
+	}
+
+	fn acknowledge(&mut self, reg: Status, bits: u32) {
+		match reg {
+			Status::Gintsts => self.gintsts &= !(bits & W1C),
+			Status::Diepint(n) => self.diepint[n as usize] &= !(bits & !TXFE),
+			Status::Doepint(n) => self.doepint[n as usize] &= !bits,
+			Status::Gotgint => (),
+		}
+		self.sync();
+	}
+
+	fn soft_reset(&mut self) {
+		self.rx_status.clear();
+		self.rx_fifo.clear();
+		for fifo in self.tx_fifo.iter_mut() {
+			fifo.clear();
+		}
+		self.sync();
+	}
+
+	// TXFNUM 0x10 flushes all TX FIFOs
+	fn flush_tx(&mut self, fifo: u8) {
+		for (i, words) in self.tx_fifo.iter_mut().enumerate() {
+			if fifo == 0x10 || fifo as usize == i {
+				words.clear();
+			}
+		}
+		self.sync();
+	}
+
+	// in slave mode the global OUT NAK takes effect once its entry in the receive status
+	// queue is popped
+	fn global_out_nak(&mut self, nak: bool) {
+		if nak {
+			if !self.out_nak {
+				self.rx_status.push_back((GLOBAL_OUT_NAK as u32) << 17);
+			}
+		} else {
+			self.out_nak = false;
+		}
+		self.sync();
+	}
+
+	fn disable_in(&mut self, ep: u8) -> bool {
+		let ctl = self.in_ctl(ep);
+		self.set_in_ctl(ep, ctl | EPDIS);
+		self.disable(ep);
+		self.diepint[ep as usize] &= !EPDISD;
+		self.sync();
+		true
+	}
+
+	fn disable_out(&mut self, ep: u8) -> bool {
+		assert!(self.out_nak);
+		let ctl = self.out_ctl(ep);
+		self.set_out_ctl(ep, ctl | EPDIS);
+		self.disable(ep);
+		self.doepint[ep as usize] &= !EPDISD;
+		self.sync();
+		true
+	}
+
+	fn unique_id(&mut self) -> [u32; 3] {
+		UNIQUE_ID
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::descriptor;
+	use super::super::request;
+	use super::super::cdc::{self, LineCoding};
+	use super::super::error::UsbError;
+	use super::super::pool;
+	use super::super::hid;
+	use super::super::keyboard;
+	use super::super::serial::{self, Buffers};
+	use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+	use std::thread;
+
+	// The receive pool is global, tests that configure the device take turns with it.
+	static POOL_IN_USE : AtomicBool = ATOMIC_BOOL_INIT;
+
+	struct PoolLock;
+
+	fn lock_pool() -> PoolLock {
+		while POOL_IN_USE.compare_and_swap(false, true, Ordering::Acquire) {
+			thread::yield_now();
+		}
+		PoolLock
+	}
+
+	impl Drop for PoolLock {
+		fn drop(&mut self) {
+			POOL_IN_USE.store(false, Ordering::Release);
+		}
+	}
+
+	// Bus reset and enumeration at high speed.
+	fn enumerate(driver: &mut Driver<Simulator>) {
+		driver.hw().bus_reset();
+		driver.run();
+		driver.hw().enumeration_done(0);
+		driver.run();
+	}
+
+	// A device that went through a bus reset and enumerated at high speed.
+	fn enumerated() -> Driver<Simulator> {
+		let mut driver = Driver::new(Simulator::new());
+		driver.start();
+		enumerate(&mut driver);
+		driver
+	}
+
+	// Control read: the data of all IN packets, then the zero length OUT status packet.
+	fn control_in(driver: &mut Driver<Simulator>, setup: [u8; 8]) -> Vec<u8> {
+		driver.hw().setup(&setup);
+		driver.run();
+		let mut data = Vec::new();
+		while let Some(packet) = driver.hw().pop_in(0) {
+			data.extend_from_slice(&packet);
+		}
+		assert!(driver.hw().out(0, &[]), "status stage NAKed");
+		driver.run();
+		data
+	}
+
+	// Control write: the OUT data packets, then the zero length IN status packet.
+	fn control_out(driver: &mut Driver<Simulator>, setup: [u8; 8], data: &[u8]) {
+		driver.hw().setup(&setup);
+		driver.run();
+		for chunk in data.chunks(64) {
+			assert!(driver.hw().out(0, chunk), "data stage NAKed");
+			driver.run();
+		}
+		assert_eq!(driver.hw().pop_in(0), Some(Vec::new()));
+	}
+
+	fn configure(driver: &mut Driver<Simulator>) {
+		control_out(driver, [0x00, request::SET_ADDRESS, 3, 0, 0, 0, 0, 0], &[]);
+		control_out(driver, [0x00, request::SET_CONFIGURATION, 1, 0, 0, 0, 0, 0], &[]);
+	}
+
+	// A device in the configured state, the receive pool is its own until the lock goes.
+	fn configured() -> (Driver<Simulator>, PoolLock) {
+		let lock = lock_pool();
+		let mut driver = enumerated();
+		configure(&mut driver);
+		while pool::RX.receive().is_some() {}
+		(driver, lock)
+	}
+
+	fn device_descriptor() -> Vec<u8> {
+		let mut buf = [0u8; 18];
+		let len = descriptor::get(descriptor::DEVICE, 0, &mut buf).unwrap();
+		buf[..len].to_vec()
+	}
+
+	#[test]
+	fn enumeration_leaves_nothing_pending() {
+		let mut driver = enumerated();
+		assert!(!driver.hw().pending());
+		assert_eq!(driver.take_error(), None);
+	}
+
+	#[test]
+	fn full_speed_is_reported() {
+		let mut driver = Driver::new(Simulator::new());
+		driver.start();
+		driver.hw().bus_reset();
+		driver.hw().enumeration_done(0x3);
+		driver.run();
+		assert_eq!(driver.take_error(), Some(UsbError::UnsupportedSpeed(0x3)));
+	}
+
+	#[test]
+	fn get_device_descriptor() {
+		let mut driver = enumerated();
+		let data = control_in(&mut driver, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 64, 0]);
+		assert_eq!(data, device_descriptor());
+		// the first request of a host that does not know bMaxPacketSize0 yet
+		let data = control_in(&mut driver, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 8, 0]);
+		assert_eq!(data, device_descriptor()[..8].to_vec());
+	}
+
+	#[test]
+	fn get_configuration_descriptor() {
+		let mut driver = enumerated();
+		let mut buf = [0u8; 512];
+		let len = descriptor::configuration(descriptor::TREE, &mut buf).unwrap();
+		let data = control_in(&mut driver, [0x80, request::GET_DESCRIPTOR, 0, descriptor::CONFIGURATION, 0, 0, 0xff, 0x01]);
+		assert_eq!(data, buf[..len].to_vec());
+	}
+
+	#[test]
+	fn set_address_programs_dad() {
+		let mut driver = enumerated();
+		driver.hw().setup(&[0x00, request::SET_ADDRESS, 7, 0, 0, 0, 0, 0]);
+		driver.run();
+		assert_eq!(driver.hw().address(), 7);
+		assert_eq!(driver.hw().pop_in(0), Some(Vec::new()));
+		assert_eq!(driver.device().address(), 7);
+		let data = control_in(&mut driver, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 64, 0]);
+		assert_eq!(data, device_descriptor());
+	}
+
+	#[test]
+	fn back_to_back_setup_answers_the_last_one() {
+		let mut driver = enumerated();
+		driver.hw().setup(&[0x00, request::SET_ADDRESS, 9, 0, 0, 0, 0, 0]);
+		let data = control_in(&mut driver, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 64, 0]);
+		assert_eq!(data, device_descriptor());
+		assert_eq!(driver.hw().address(), 0);
+	}
+
+	#[test]
+	fn out_data_stage() {
+		let (mut driver, _pool) = configured();
+		let interface = cdc::COMM_INTERFACE;
+		let coding = [0x00, 0xc2, 0x01, 0x00, 2, 1, 7];
+		control_out(&mut driver, [0x21, cdc::SET_LINE_CODING, 0, 0, interface, 0, 7, 0], &coding);
+		assert_eq!(driver.acm().line_coding(), LineCoding { rate: 115200, stop_bits: 2, parity: 1, data_bits: 7 });
+		let data = control_in(&mut driver, [0xa1, cdc::GET_LINE_CODING, 0, 0, interface, 0, 7, 0]);
+		assert_eq!(data, coding.to_vec());
+	}
+
+	#[test]
+	fn mode_mismatch_is_recovered() {
+		let mut driver = enumerated();
+		driver.hw().mode_mismatch();
+		assert!(driver.hw().pending());
+		driver.run();
+		assert!(!driver.hw().pending());
+		assert_eq!(driver.take_error(), Some(UsbError::ModeMismatch));
+		let data = control_in(&mut driver, [0x80, request::GET_DESCRIPTOR, 0, descriptor::DEVICE, 0, 0, 64, 0]);
+		assert_eq!(data, device_descriptor());
+	}
+
+	#[test]
+	fn bulk_out_goes_to_the_receive_pool() {
+		let (mut driver, _pool) = configured();
+		assert!(driver.hw().out(cdc::DATA_OUT_EP, b"hello"));
+		driver.run();
+		// the endpoint NAKs until the driver gave it the next buffer
+		assert!(driver.hw().out(cdc::DATA_OUT_EP, b"world"));
+		driver.run();
+		let rx = pool::RX.receive().unwrap();
+		assert_eq!((rx.ep(), rx.data()), (cdc::DATA_OUT_EP, &b"hello"[..]));
+		let rx = pool::RX.receive().unwrap();
+		assert_eq!((rx.ep(), rx.data()), (cdc::DATA_OUT_EP, &b"world"[..]));
+		assert!(pool::RX.receive().is_none());
+	}
+
+	#[test]
+	fn deconfiguration_disables_the_data_endpoints() {
+		let (mut driver, _pool) = configured();
+		// still in the RX FIFO when the request comes in
+		assert!(driver.hw().out(cdc::DATA_OUT_EP, b"last"));
+		control_out(&mut driver, [0x00, request::SET_CONFIGURATION, 0, 0, 0, 0, 0, 0], &[]);
+		assert_eq!(driver.take_error(), None);
+		let rx = pool::RX.receive().unwrap();
+		assert_eq!((rx.ep(), rx.data()), (cdc::DATA_OUT_EP, &b"last"[..]));
+		assert!(!driver.hw().out(cdc::DATA_OUT_EP, b"late"));
+		assert!(!driver.hw().global().otg_hs_gintsts.read().boutnakeff());
+
+		control_out(&mut driver, [0x00, request::SET_CONFIGURATION, 1, 0, 0, 0, 0, 0], &[]);
+		assert!(driver.hw().out(cdc::DATA_OUT_EP, b"again"));
+		driver.run();
+		let rx = pool::RX.receive().unwrap();
+		assert_eq!((rx.ep(), rx.data()), (cdc::DATA_OUT_EP, &b"again"[..]));
+	}
+
+	#[test]
+	fn restarts_give_the_receive_buffers_back() {
+		let (mut driver, _pool) = configured();
+		// more often than the pool has buffers, each time with the data endpoints holding some
+		for _ in 0..pool::POOL_LEN + 1 {
+			driver.start();
+			enumerate(&mut driver);
+			configure(&mut driver);
+		}
+		for _ in 0..pool::POOL_LEN + 1 {
+			enumerate(&mut driver);
+			configure(&mut driver);
+		}
+		// a new driver for every usbip connection
+		for _ in 0..pool::POOL_LEN + 1 {
+			driver = enumerated();
+			configure(&mut driver);
+		}
+		assert!(driver.hw().out(cdc::DATA_OUT_EP, b"still"));
+		driver.run();
+		let rx = pool::RX.receive().unwrap();
+		assert_eq!((rx.ep(), rx.data()), (cdc::DATA_OUT_EP, &b"still"[..]));
+		assert_eq!(driver.take_error(), None);
+	}
+
+	#[test]
+	fn bulk_in_packet_reaches_the_host() {
+		let (mut driver, _pool) = configured();
+		let data = [0x5a; 600];
+		assert_eq!(driver.send(cdc::DATA_IN_EP, &data), 512);
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), Some(data[..512].to_vec()));
+		assert_eq!(driver.send(cdc::DATA_IN_EP, &data[512..]), 88);
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), Some(data[512..].to_vec()));
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), None);
+	}
+
+	#[test]
+	fn halted_endpoint_stalls_until_cleared() {
+		let (mut driver, _pool) = configured();
+		let halt = [0x02, request::SET_FEATURE, 0, 0, cdc::DATA_OUT_EP, 0, 0, 0];
+		control_out(&mut driver, halt, &[]);
+		assert!(driver.hw().stalled(cdc::DATA_OUT_EP));
+		assert!(!driver.hw().out(cdc::DATA_OUT_EP, b"x"));
+
+		let clear = [0x02, request::CLEAR_FEATURE, 0, 0, cdc::DATA_OUT_EP, 0, 0, 0];
+		control_out(&mut driver, clear, &[]);
+		assert!(!driver.hw().stalled(cdc::DATA_OUT_EP));
+		assert!(driver.hw().out(cdc::DATA_OUT_EP, b"x"));
+	}
+
+	#[test]
+	fn idle_rate_repeats_keyboard_reports() {
+		let (mut driver, _pool) = configured();
+		let report = [0, 0, 4, 0, 0, 0, 0, 0];
+		assert!(driver.send_report(keyboard::INTERFACE, &report));
+		driver.run();
+		assert_eq!(driver.hw().pop_in(keyboard::IN_EP), Some(report.to_vec()));
+		// without an idle rate the SOF interrupt stays masked
+		assert!(!driver.hw().global().otg_hs_gintmsk.read().sofm());
+
+		// 4 ms
+		control_out(&mut driver, [0x21, hid::SET_IDLE, 0, 1, keyboard::INTERFACE, 0, 0, 0], &[]);
+		for _ in 1..4 * hid::FRAMES_PER_MS {
+			driver.hw().sof();
+			driver.run();
+		}
+		assert_eq!(driver.hw().pop_in(keyboard::IN_EP), None);
+		driver.hw().sof();
+		driver.run();
+		assert_eq!(driver.hw().pop_in(keyboard::IN_EP), Some(report.to_vec()));
+		assert_eq!(driver.hw().pop_in(keyboard::IN_EP), None);
+
+		control_out(&mut driver, [0x21, hid::SET_IDLE, 0, 0, keyboard::INTERFACE, 0, 0, 0], &[]);
+		assert!(!driver.hw().global().otg_hs_gintmsk.read().sofm());
+	}
+
+	// The host sets DTR on the serial port.
+	fn open_port(driver: &mut Driver<Simulator>) {
+		let dtr = [0x21, cdc::SET_CONTROL_LINE_STATE, 1, 0, cdc::COMM_INTERFACE, 0, 0, 0];
+		control_out(driver, dtr, &[]);
+	}
+
+	#[test]
+	fn serial_ends_full_packets_with_a_zero_length_packet() {
+		let (mut driver, _pool) = configured();
+		open_port(&mut driver);
+		let mut port = Buffers::new();
+		let data = [0x5a; 512];
+		assert_eq!(port.write(&mut driver, &data), Some(512));
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), Some(data.to_vec()));
+		port.poll(&mut driver);
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), Some(Vec::new()));
+		port.poll(&mut driver);
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), None);
+
+		// a short packet ends the transfer by itself
+		assert_eq!(port.write(&mut driver, b"short"), Some(5));
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), Some(b"short".to_vec()));
+		port.poll(&mut driver);
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), None);
+	}
+
+	#[test]
+	fn serial_write_gives_up_when_the_host_stops_reading() {
+		let (mut driver, _pool) = configured();
+		let mut port = Buffers::new();
+		let data = [0x5a; 4096];
+		assert!(serial::write_all(&data, |data| port.write(&mut driver, data)).is_err());
+
+		open_port(&mut driver);
+		driver.hw().pause_in(cdc::DATA_IN_EP, true);
+		assert!(serial::write_all(&data, |data| port.write(&mut driver, data)).is_err());
+		// what was queued goes out once the host reads again
+		driver.hw().pause_in(cdc::DATA_IN_EP, false);
+		driver.run();
+		assert_eq!(driver.hw().pop_in(cdc::DATA_IN_EP), Some(data[..512].to_vec()));
+	}
+}
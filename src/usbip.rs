@@ -0,0 +1,460 @@
+// USB/IP server exporting the device stack running on the simulated core, so that a
+// local Linux host can attach it through vhci-hcd:
+//
+//	modprobe vhci-hcd
+//	usbip attach -r 127.0.0.1 -b 1-1
+//
+// The serial port echoes what the host writes to it, the HID functions send no reports.
+//
+// Protocol: https://www.kernel.org/doc/html/latest/usb/usbip_protocol.html
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+use usb::driver::Driver;
+use usb::sim::Simulator;
+use usb::pool;
+use usb::cdc;
+
+const VERSION : u16 = 0x0111;
+const OP_REQ_DEVLIST : u16 = 0x8005;
+const OP_REP_DEVLIST : u16 = 0x0005;
+const OP_REQ_IMPORT : u16 = 0x8003;
+const OP_REP_IMPORT : u16 = 0x0003;
+const CMD_SUBMIT : u32 = 0x1;
+const CMD_UNLINK : u32 = 0x2;
+const RET_SUBMIT : u32 = 0x3;
+const RET_UNLINK : u32 = 0x4;
+const DIR_IN : u32 = 1;
+
+const BUSID : &'static str = "1-1";
+const PATH : &'static str = "/sys/devices/platform/stm32f7-discovery/usb1/1-1";
+const BUSNUM : u32 = 1;
+const DEVNUM : u32 = 2;
+const SPEED_HIGH : u32 = 3;
+
+// negated errno values reported in RET_SUBMIT.status and RET_UNLINK.status
+const EPIPE : i32 = -32;
+const ECONNRESET : i32 = -104;
+const ETIMEDOUT : i32 = -110;
+
+// how long the server waits for a command before it lets the device run again
+const POLL_INTERVAL_MS : u64 = 1;
+
+pub fn serve(addr: &str) -> io::Result<()> {
+	let listener = try!(TcpListener::bind(addr));
+	println!("usbip: listening on {}, busid {}", addr, BUSID);
+	for stream in listener.incoming() {
+		let stream = try!(stream);
+		if let Err(e) = handle(stream) {
+			println!("usbip: connection closed: {}", e);
+		}
+	}
+	Ok(())
+}
+
+fn handle(mut stream: TcpStream) -> io::Result<()> {
+	let mut header = [0u8; 8];
+	try!(stream.read_exact(&mut header));
+	let code = be16(&header[2..4]);
+	let mut device = Device::new();
+	match code {
+		OP_REQ_DEVLIST => {
+			let mut reply = Vec::new();
+			put16(&mut reply, VERSION);
+			put16(&mut reply, OP_REP_DEVLIST);
+			put32(&mut reply, 0);
+			put32(&mut reply, 1);
+			device.put_info(&mut reply);
+			for interface in interfaces(&device.config) {
+				reply.extend_from_slice(&[interface.0, interface.1, interface.2, 0]);
+			}
+			stream.write_all(&reply)
+		},
+		OP_REQ_IMPORT => {
+			let mut busid = [0u8; 32];
+			try!(stream.read_exact(&mut busid));
+			let found = busid.iter().take_while(|b| **b != 0).cloned().eq(BUSID.bytes());
+			let mut reply = Vec::new();
+			put16(&mut reply, VERSION);
+			put16(&mut reply, OP_REP_IMPORT);
+			put32(&mut reply, if found { 0 } else { 1 });
+			if found {
+				// vhci-hcd answers SET_ADDRESS itself and never forwards it, the device
+				// gets it here instead
+				let _ = device.control([0x00, 5, DEVNUM as u8, 0, 0, 0, 0, 0], &[]);
+				device.put_info(&mut reply);
+			}
+			try!(stream.write_all(&reply));
+			if found {
+				let result = urbs(&mut stream, device);
+				let _ = stream.shutdown(Shutdown::Both);
+				result
+			} else {
+				Ok(())
+			}
+		},
+		_ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown operation")),
+	}
+}
+
+// CMD_SUBMIT or CMD_UNLINK, with the data of an OUT transfer
+struct Command {
+	header: [u8; 48],
+	out: Vec<u8>,
+}
+
+fn read_command(stream: &mut TcpStream) -> io::Result<Command> {
+	let mut header = [0u8; 48];
+	try!(stream.read_exact(&mut header));
+	let submit_out = be32(&header[0..4]) == CMD_SUBMIT && be32(&header[12..16]) != DIR_IN;
+	let mut out = vec![0u8; if submit_out { be32(&header[24..28]) as usize } else { 0 }];
+	try!(stream.read_exact(&mut out));
+	Ok(Command { header: header, out: out })
+}
+
+// Reads the commands of the host on a thread of its own, so that the device keeps
+// running while the host waits for transfers to complete.
+fn commands(stream: &TcpStream) -> io::Result<Receiver<io::Result<Command>>> {
+	let mut stream = try!(stream.try_clone());
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || loop {
+		let command = read_command(&mut stream);
+		let failed = command.is_err();
+		if tx.send(command).is_err() || failed {
+			return;
+		}
+	});
+	Ok(rx)
+}
+
+// A CMD_SUBMIT on a data endpoint the device has not completed yet
+struct Urb {
+	seqnum: u32,
+	// bEndpointAddress
+	address: u8,
+	length: usize,
+	// IN: data received so far, OUT: data to send
+	data: Vec<u8>,
+	// OUT: bytes the device took so far
+	sent: usize,
+}
+
+fn urbs(stream: &mut TcpStream, mut device: Device) -> io::Result<()> {
+	let commands = try!(commands(stream));
+	let mut pending: Vec<Urb> = Vec::new();
+	loop {
+		match commands.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+			Ok(command) => {
+				let command = try!(command);
+				if let Some(reply) = try!(submit_or_unlink(&mut device, &mut pending, command)) {
+					try!(stream.write_all(&reply));
+				}
+			},
+			Err(RecvTimeoutError::Timeout) => (),
+			Err(RecvTimeoutError::Disconnected) => return Ok(()),
+		}
+
+		device.poll();
+		// transfers on one endpoint complete in order
+		let mut waiting = 0u32;
+		let mut i = 0;
+		while i < pending.len() {
+			let bit = 1 << ((pending[i].address & 0xf) + if pending[i].address & 0x80 != 0 { 16 } else { 0 });
+			let done = if waiting & bit == 0 { device.transfer(&mut pending[i]) } else { None };
+			match done {
+				Some(status) => {
+					let urb = pending.remove(i);
+					let (data, actual) = if urb.address & 0x80 != 0 {
+						(&urb.data[..], urb.data.len())
+					} else {
+						(&[][..], urb.sent)
+					};
+					try!(stream.write_all(&ret_submit(urb.seqnum, status, actual, data)));
+				},
+				None => {
+					waiting |= bit;
+					i += 1;
+				},
+			}
+		}
+	}
+}
+
+// Control transfers complete right away, transfers on the data endpoints are queued
+// until the device takes or provides the data. Returns the reply to send now.
+fn submit_or_unlink(device: &mut Device, pending: &mut Vec<Urb>, command: Command) -> io::Result<Option<Vec<u8>>> {
+	let header = command.header;
+	let seqnum = be32(&header[4..8]);
+	let direction = be32(&header[12..16]);
+	let ep = be32(&header[16..20]) as u8 & 0xf;
+	match be32(&header[0..4]) {
+		CMD_SUBMIT if ep == 0 => {
+			let length = be32(&header[24..28]) as usize;
+			let mut setup = [0u8; 8];
+			setup.copy_from_slice(&header[40..48]);
+			let reply = match device.control(setup, &command.out) {
+				Ok(mut data) => {
+					data.truncate(length);
+					let actual = if direction == DIR_IN { data.len() } else { command.out.len() };
+					ret_submit(seqnum, 0, actual, &data)
+				},
+				Err(status) => ret_submit(seqnum, status, 0, &[]),
+			};
+			Ok(Some(reply))
+		},
+		CMD_SUBMIT => {
+			let urb = Urb {
+				seqnum: seqnum,
+				address: ep | if direction == DIR_IN { 0x80 } else { 0 },
+				length: be32(&header[24..28]) as usize,
+				data: command.out,
+				sent: 0,
+			};
+			pending.push(urb);
+			Ok(None)
+		},
+		CMD_UNLINK => {
+			let unlink = be32(&header[20..24]);
+			// an URB that already completed has nothing left to unlink
+			let status = match pending.iter().position(|urb| urb.seqnum == unlink) {
+				Some(i) => {
+					pending.remove(i);
+					ECONNRESET
+				},
+				None => 0,
+			};
+			let mut reply = Vec::new();
+			put32(&mut reply, RET_UNLINK);
+			put32(&mut reply, seqnum);
+			reply.extend_from_slice(&[0u8; 12]);
+			put32(&mut reply, status as u32);
+			reply.extend_from_slice(&[0u8; 24]);
+			Ok(Some(reply))
+		},
+		_ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown command")),
+	}
+}
+
+fn ret_submit(seqnum: u32, status: i32, actual: usize, data: &[u8]) -> Vec<u8> {
+	let mut reply = Vec::new();
+	put32(&mut reply, RET_SUBMIT);
+	put32(&mut reply, seqnum);
+	reply.extend_from_slice(&[0u8; 12]);
+	put32(&mut reply, status as u32);
+	put32(&mut reply, actual as u32);
+	reply.extend_from_slice(&[0u8; 20]);
+	reply.extend_from_slice(data);
+	reply
+}
+
+struct Device {
+	driver: Driver<Simulator>,
+	mps0: usize,
+	device: Vec<u8>,
+	config: Vec<u8>,
+	// serial port data received and not echoed yet
+	echo: Vec<u8>,
+}
+
+impl Device {
+	// Powers up a fresh simulated core, resets the bus and reads the descriptors the
+	// import reply needs.
+	fn new() -> Device {
+		let mut driver = Driver::new(Simulator::new());
+		driver.start();
+		driver.hw().bus_reset();
+		driver.run();
+		driver.hw().enumeration_done(0);
+		driver.run();
+
+		let mut device = Device { driver: driver, mps0: 8, device: Vec::new(), config: Vec::new(), echo: Vec::new() };
+		device.device = device.control([0x80, 6, 0, 1, 0, 0, 18, 0], &[]).unwrap_or(Vec::new());
+		if device.device.len() > 7 {
+			device.mps0 = device.device[7] as usize;
+		}
+		device.config = device.control([0x80, 6, 0, 2, 0, 0, 0xff, 0xff], &[]).unwrap_or(Vec::new());
+		device
+	}
+
+	// The application side of the simulated device: echoes what the host writes to the
+	// serial port.
+	fn poll(&mut self) {
+		self.driver.run();
+		if pool::RX.take_starved() {
+			self.driver.rearm();
+		}
+		while let Some(rx) = pool::RX.receive() {
+			if rx.ep() == cdc::DATA_OUT_EP {
+				self.echo.extend_from_slice(rx.data());
+			}
+		}
+		if !self.echo.is_empty() {
+			let sent = self.driver.send(cdc::DATA_IN_EP, &self.echo);
+			self.echo.drain(..sent);
+		}
+		self.driver.run();
+	}
+
+	// Runs one control transfer through the simulated core and returns the data stage
+	// of an IN transfer.
+	fn control(&mut self, setup: [u8; 8], out: &[u8]) -> Result<Vec<u8>, i32> {
+		let length = setup[6] as usize | (setup[7] as usize) << 8;
+		while self.driver.hw().pop_in(0).is_some() {}
+
+		self.driver.hw().setup(&setup);
+		self.driver.run();
+		if setup[0] & 0x80 != 0 {
+			let mut data = Vec::new();
+			while data.len() < length {
+				match self.driver.hw().pop_in(0) {
+					Some(packet) => {
+						let short = packet.len() < self.mps0;
+						data.extend_from_slice(&packet);
+						if short {
+							break;
+						}
+					},
+					None if data.is_empty() => return Err(self.no_reply(0x80)),
+					None => break,
+				}
+				self.driver.run();
+			}
+			self.driver.hw().out(0, &[]);
+			self.driver.run();
+			Ok(data)
+		} else {
+			for packet in out.chunks(self.mps0) {
+				if !self.driver.hw().out(0, packet) {
+					return Err(EPIPE);
+				}
+				self.driver.run();
+			}
+			match self.driver.hw().pop_in(0) {
+				Some(_) => Ok(Vec::new()),
+				None => Err(self.no_reply(0x80)),
+			}
+		}
+	}
+
+	// status of a transfer endpoint `address` did not answer
+	fn no_reply(&mut self, address: u8) -> i32 {
+		if self.driver.hw().stalled(address) { EPIPE } else { ETIMEDOUT }
+	}
+
+	// Moves a transfer on a data endpoint on as far as the device lets it, returns the
+	// RET_SUBMIT status once it is complete. A short packet ends an IN transfer.
+	fn transfer(&mut self, urb: &mut Urb) -> Option<i32> {
+		let mps = endpoints(&self.config).iter()
+			.find(|ep| ep.0 == urb.address)
+			.map(|ep| ep.1);
+		let mps = match mps {
+			Some(mps) if mps > 0 => mps,
+			_ => return Some(EPIPE),
+		};
+		let n = urb.address & 0xf;
+		if self.driver.hw().stalled(urb.address) {
+			return Some(EPIPE);
+		}
+		if urb.address & 0x80 != 0 {
+			while urb.data.len() < urb.length {
+				match self.driver.hw().pop_in(n) {
+					Some(packet) => {
+						let left = urb.length - urb.data.len();
+						urb.data.extend_from_slice(&packet[..::std::cmp::min(packet.len(), left)]);
+						if packet.len() < mps {
+							return Some(0);
+						}
+					},
+					None => return None,
+				}
+				self.driver.run();
+			}
+			Some(0)
+		} else {
+			loop {
+				let end = ::std::cmp::min(urb.sent + mps, urb.data.len());
+				if !self.driver.hw().out(n, &urb.data[urb.sent..end]) {
+					return if self.driver.hw().stalled(urb.address) { Some(EPIPE) } else { None };
+				}
+				urb.sent = end;
+				self.driver.run();
+				if urb.sent == urb.data.len() {
+					return Some(0);
+				}
+			}
+		}
+	}
+
+	// usbip_usb_device as sent in OP_REP_DEVLIST and OP_REP_IMPORT
+	fn put_info(&self, buf: &mut Vec<u8>) {
+		let mut path = [0u8; 256];
+		path[..PATH.len()].copy_from_slice(PATH.as_bytes());
+		buf.extend_from_slice(&path);
+		let mut busid = [0u8; 32];
+		busid[..BUSID.len()].copy_from_slice(BUSID.as_bytes());
+		buf.extend_from_slice(&busid);
+		put32(buf, BUSNUM);
+		put32(buf, DEVNUM);
+		put32(buf, SPEED_HIGH);
+
+		let mut desc = [0u8; 18];
+		let len = ::std::cmp::min(self.device.len(), desc.len());
+		desc[..len].copy_from_slice(&self.device[..len]);
+		put16(buf, le16(&desc[8..10]));
+		put16(buf, le16(&desc[10..12]));
+		put16(buf, le16(&desc[12..14]));
+		buf.extend_from_slice(&[desc[4], desc[5], desc[6]]);
+		let value = if self.config.len() > 5 { self.config[5] } else { 0 };
+		buf.extend_from_slice(&[value, desc[17], interfaces(&self.config).len() as u8]);
+	}
+}
+
+// (bEndpointAddress, wMaxPacketSize) of the endpoints in a configuration descriptor
+fn endpoints(config: &[u8]) -> Vec<(u8, usize)> {
+	let mut endpoints = Vec::new();
+	let mut i = 0;
+	while i + 1 < config.len() && config[i] != 0 {
+		if config[i + 1] == 5 && i + 5 < config.len() {
+			endpoints.push((config[i + 2], (le16(&config[i + 4..i + 6]) & 0x7ff) as usize));
+		}
+		i += config[i] as usize;
+	}
+	endpoints
+}
+
+// (class, subclass, protocol) of the interfaces in a configuration descriptor
+fn interfaces(config: &[u8]) -> Vec<(u8, u8, u8)> {
+	let mut interfaces = Vec::new();
+	let mut i = 0;
+	while i + 1 < config.len() && config[i] != 0 {
+		if config[i + 1] == 4 && i + 8 < config.len() && config[i + 3] == 0 {
+			interfaces.push((config[i + 5], config[i + 6], config[i + 7]));
+		}
+		i += config[i] as usize;
+	}
+	interfaces
+}
+
+fn be16(b: &[u8]) -> u16 {
+	(b[0] as u16) << 8 | b[1] as u16
+}
+
+fn be32(b: &[u8]) -> u32 {
+	(b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+}
+
+fn le16(b: &[u8]) -> u16 {
+	(b[1] as u16) << 8 | b[0] as u16
+}
+
+fn put16(buf: &mut Vec<u8>, v: u16) {
+	buf.extend_from_slice(&[(v >> 8) as u8, v as u8]);
+}
+
+fn put32(buf: &mut Vec<u8>, v: u32) {
+	buf.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}